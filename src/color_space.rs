@@ -0,0 +1,210 @@
+use sdl2::pixels::Color;
+
+use util;
+
+/*
+ * Conversions between sRGB colors and other color spaces.
+ * Hue is in degrees (0..360), everything else in 0..1 unless noted.
+ */
+
+pub fn to_hsv(color: Color) -> (f32, f32, f32) {
+    let (r,g,b,_) = util::color_to_rgba(color);
+    let (r,g,b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * (((g - b) / delta) % 6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (wrap_hue(hue), saturation, max)
+}
+
+pub fn from_hsv(hue: f32, saturation: f32, value: f32, alpha: u8) -> Color {
+    let (hue, saturation, value) = (wrap_hue(hue), clamp(saturation), clamp(value));
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = value - chroma;
+    let (r,g,b) = match (hue / 60.0) as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    rgba(r + m, g + m, b + m, alpha)
+}
+
+pub fn to_hsl(color: Color) -> (f32, f32, f32) {
+    let (hue, saturation, value) = to_hsv(color);
+    let lightness = value * (1.0 - saturation / 2.0);
+    let saturation = if lightness == 0.0 || lightness == 1.0 {
+        0.0
+    } else {
+        (value - lightness) / lightness.min(1.0 - lightness)
+    };
+    (hue, saturation, lightness)
+}
+
+pub fn from_hsl(hue: f32, saturation: f32, lightness: f32, alpha: u8) -> Color {
+    let (saturation, lightness) = (clamp(saturation), clamp(lightness));
+    let value = lightness + saturation * lightness.min(1.0 - lightness);
+    let saturation = if value == 0.0 { 0.0 } else { 2.0 * (1.0 - lightness / value) };
+    from_hsv(hue, saturation, value, alpha)
+}
+
+/*
+ * OKLab, a perceptual color space where euclidean distance roughly
+ * matches perceived difference. L is 0..1, a and b are roughly -0.4..0.4.
+ */
+pub fn to_oklab(color: Color) -> (f32, f32, f32) {
+    let (r,g,b,_) = util::color_to_rgba(color);
+    let (r,g,b) = (to_linear(r), to_linear(g), to_linear(b));
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    (0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+     1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+     0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s)
+}
+
+pub fn from_oklab(lightness: f32, a: f32, b: f32, alpha: u8) -> Color {
+    let l = lightness + 0.3963377774 * a + 0.2158037573 * b;
+    let m = lightness - 0.1055613458 * a - 0.0638541728 * b;
+    let s = lightness - 0.0894841775 * a - 1.2914855480 * b;
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+
+    rgba(from_linear( 4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+         from_linear(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+         from_linear(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+         alpha)
+}
+
+/*
+ * Squared euclidean distance in OKLab, alpha is ignored
+ */
+pub fn oklab_distance(a: (f32, f32, f32), b: (f32, f32, f32)) -> f32 {
+    let (dl, da, db) = (a.0 - b.0, a.1 - b.1, a.2 - b.2);
+    dl * dl + da * da + db * db
+}
+
+pub fn wrap_hue(hue: f32) -> f32 {
+    ((hue % 360.0) + 360.0) % 360.0
+}
+
+fn clamp(value: f32) -> f32 {
+    value.max(0.0).min(1.0)
+}
+
+fn rgba(r: f32, g: f32, b: f32, alpha: u8) -> Color {
+    let channel = |value: f32| (clamp(value) * 255.0).round() as u8;
+    if alpha == 255 {
+        Color::RGB(channel(r), channel(g), channel(b))
+    } else {
+        Color::RGBA(channel(r), channel(g), channel(b), alpha)
+    }
+}
+
+fn to_linear(channel: u8) -> f32 {
+    let channel = channel as f32 / 255.0;
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn from_linear(channel: f32) -> f32 {
+    if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.max(0.0).powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use util::color_to_rgba;
+    use super::*;
+
+    fn colors() -> Vec<Color> {
+        vec![Color::RGB(0,0,0), Color::RGB(255,255,255), Color::RGB(255,0,0),
+             Color::RGB(0,128,0), Color::RGB(18,52,86), Color::RGB(250,200,10),
+             Color::RGB(128,128,128), Color::RGB(200,30,180)]
+    }
+
+    // OKLab goes through floating point powers, so a channel may round the
+    // other way
+    fn assert_close(a: Color, b: Color) {
+        let (a, b) = (color_to_rgba(a), color_to_rgba(b));
+        let near = |x: u8, y: u8| (x as i32 - y as i32).abs() <= 1;
+        assert!(near(a.0, b.0) && near(a.1, b.1) && near(a.2, b.2) && a.3 == b.3,
+                "{:?} isn't close to {:?}", a, b);
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "{} isn't near {}", a, b);
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for &color in &colors() {
+            let (h, s, v) = to_hsv(color);
+            assert_eq!(color_to_rgba(from_hsv(h, s, v, 255)), color_to_rgba(color));
+        }
+        let (h, s, v) = to_hsv(Color::RGB(0,0,255));
+        assert_near(h, 240.0);
+        assert_near(s, 1.0);
+        assert_near(v, 1.0);
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        for &color in &colors() {
+            let (h, s, l) = to_hsl(color);
+            assert_eq!(color_to_rgba(from_hsl(h, s, l, 255)), color_to_rgba(color));
+        }
+        let (h, s, l) = to_hsl(Color::RGB(255,0,0));
+        assert_near(h, 0.0);
+        assert_near(s, 1.0);
+        assert_near(l, 0.5);
+        let (_, s, l) = to_hsl(Color::RGB(255,255,255));
+        assert_near(s, 0.0);
+        assert_near(l, 1.0);
+        assert_eq!(color_to_rgba(from_hsl(120.0, 1.0, 0.25, 40)), (0,128,0,40));
+    }
+
+    #[test]
+    fn oklab_round_trip() {
+        for &color in &colors() {
+            let (l, a, b) = to_oklab(color);
+            assert_close(from_oklab(l, a, b, 255), color);
+        }
+        let (l, a, b) = to_oklab(Color::RGB(255,255,255));
+        assert_near(l, 1.0);
+        assert_near(a, 0.0);
+        assert_near(b, 0.0);
+        let (l, _, _) = to_oklab(Color::RGB(0,0,0));
+        assert_near(l, 0.0);
+        assert_eq!(oklab_distance(to_oklab(Color::RGB(1,2,3)), to_oklab(Color::RGB(1,2,3))), 0.0);
+    }
+
+    #[test]
+    fn hues_wrap() {
+        assert_eq!(wrap_hue(0.0), 0.0);
+        assert_eq!(wrap_hue(370.0), 10.0);
+        assert_eq!(wrap_hue(-90.0), 270.0);
+        assert_eq!(color_to_rgba(from_hsv(360.0, 1.0, 1.0, 255)), (255,0,0,255));
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::path::Path;
+
+use input::{Input, Command, ArgType, ALL_COMMANDS, expected_arg};
+use macros::Macro;
+
+/*
+ * Completion of the minibuffer. What's completed depends on what the
+ * command being entered expects next: command names after `M-x`, and
+ * for a string whatever that argument of the command is, like a file
+ * path or a macro name.
+ */
+pub fn complete(input: &[Input], buffer: &str, commands: &[(Vec<Input>, Command)],
+                macros: &[Macro]) -> Vec<String> {
+    match expected_arg(input, commands) {
+        Some(ArgType::String) => match entered_command(input, commands) {
+            Some(command) => complete_string(command, entered_args(input), buffer, macros),
+            None => vec![],
+        },
+        Some(_) => vec![],
+        None => complete_word(buffer, &next_words(input, commands)),
+    }
+}
+
+/*
+ * The string argument at `index` of `command`
+ */
+fn complete_string(command: Command, index: usize, buffer: &str, macros: &[Macro]) -> Vec<String> {
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    match (command, index) {
+        (Command::DescribeCommand, 0) | (Command::Apropos, 0) => {
+            let names: Vec<String> = ALL_COMMANDS.iter()
+                .map(|command| String::from(command.name()))
+                .collect();
+            complete_word(buffer, &names)
+        },
+        (Command::BindMacro, 0) => {
+            let names: Vec<String> = macros.iter().map(|macro_| macro_.name.clone()).collect();
+            complete_word(buffer, &names)
+        },
+        (Command::ExtractPalette, 0) =>
+            complete_word(buffer, &strings(&["usage", "hue", "luminance"])),
+        (Command::RemapToPalette, 0) =>
+            complete_word(buffer, &strings(&["none", "floyd-steinberg", "atkinson", "bayer"])),
+        (Command::ExportOra, 0) | (Command::ExportPng, 0) | (Command::ImportOra, 0)
+            | (Command::LoadPalette, 0) | (Command::SavePalette, 0)
+            | (Command::LoadMacros, 0) | (Command::SaveMacros, 0)
+            | (Command::LoadPng, 0) => complete_path(buffer),
+        // free text, like names and key sequences
+        _ => vec![],
+    }
+}
+
+/*
+ * The command of the first binding `input` is part of that takes an
+ * argument next
+ */
+fn entered_command(input: &[Input], commands: &[(Vec<Input>, Command)]) -> Option<Command> {
+    commands.iter()
+        .filter(|&&(ref inputstack, _)| {
+            input.len() < inputstack.len() && input == &inputstack[0..input.len()]
+        })
+        .find(|&&(ref inputstack, _)| ArgType::from_input(&inputstack[input.len()]).is_some())
+        .map(|&(_, command)| command)
+}
+
+/*
+ * How many arguments of the command have been entered so far
+ */
+fn entered_args(input: &[Input]) -> usize {
+    input.iter().filter(|input| ArgType::from_input(input).is_some()).count()
+}
+
+/*
+ * The `Input::Exact` words that can follow `input`
+ */
+fn next_words(input: &[Input], commands: &[(Vec<Input>, Command)]) -> Vec<String> {
+    let mut words: Vec<String> = commands.iter()
+        .filter(|&&(ref inputstack, _)| {
+            input.len() < inputstack.len() && input == &inputstack[0..input.len()]
+        })
+        .filter_map(|&(ref inputstack, _)| match inputstack[input.len()] {
+            Input::Exact(ref word) => Some(word.clone()),
+            _ => None,
+        })
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/*
+ * Words starting with `buffer` come first, followed by the ones that
+ * only contain its characters in order, so `exp` finds `export-png`
+ * and `epng` does too.
+ */
+fn complete_word(buffer: &str, words: &[String]) -> Vec<String> {
+    let mut prefixed: Vec<String> = words.iter()
+        .filter(|word| word.starts_with(buffer))
+        .cloned()
+        .collect();
+    let mut fuzzy: Vec<String> = words.iter()
+        .filter(|word| !word.starts_with(buffer) && is_subsequence(buffer, word))
+        .cloned()
+        .collect();
+    fuzzy.sort_by_key(|word| word.len());
+    prefixed.append(&mut fuzzy);
+    prefixed
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut chars = haystack.chars();
+    needle.chars().all(|c| chars.any(|h| h == c))
+}
+
+/*
+ * Entries of the directory in `buffer` starting with the rest of it.
+ * Directories end in a slash so completion can continue into them.
+ */
+fn complete_path(buffer: &str) -> Vec<String> {
+    let (dir, prefix) = match buffer.rfind('/') {
+        Some(index) => (&buffer[..(index + 1)], &buffer[(index + 1)..]),
+        None => ("", buffer),
+    };
+    let entries = match fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut paths = vec![];
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        // hidden files only when asked for
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        let is_dir = entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false);
+        paths.push(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }));
+    }
+    paths.sort();
+    paths
+}
+
+/*
+ * The longest string all `candidates` start with
+ */
+pub fn common_prefix(candidates: &[String]) -> String {
+    let first = match candidates.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut length = first.len();
+    for candidate in &candidates[1..] {
+        length = first.char_indices()
+            .zip(candidate.chars())
+            .take_while(|&((index, a), b)| index < length && a == b)
+            .map(|((index, a), _)| index + a.len_utf8())
+            .last()
+            .unwrap_or(0);
+    }
+    first[..length].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env,fs};
+    use input::{Input, Command, META_X};
+    use macros::Macro;
+    use super::{complete, complete_word, common_prefix, is_subsequence};
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    fn commands() -> Vec<(Vec<Input>, Command)> {
+        vec![(vec![META_X, Input::Exact(String::from("export-png")), Input::String],
+              Command::ExportPng),
+             (vec![META_X, Input::Exact(String::from("export-ora")), Input::String],
+              Command::ExportOra),
+             (vec![META_X, Input::Exact(String::from("zoom-in"))], Command::ZoomIn),
+             (vec![META_X, Input::Exact(String::from("set-color")), Input::Color],
+              Command::SetColor),
+             (vec![META_X, Input::Exact(String::from("describe-command")), Input::String],
+              Command::DescribeCommand),
+             (vec![META_X, Input::Exact(String::from("bind-macro")), Input::String,
+                   Input::String], Command::BindMacro),
+             (vec![META_X, Input::Exact(String::from("extract-palette")), Input::String,
+                   Input::Integer], Command::ExtractPalette),
+             (vec![META_X, Input::Exact(String::from("remap-to-palette")), Input::String],
+              Command::RemapToPalette),
+             (vec![META_X, Input::Exact(String::from("print")), Input::String], Command::Print)]
+    }
+
+    #[test]
+    fn prefixes_before_subsequences() {
+        let words = strings(&["export-png", "export-ora", "zoom-out", "png"]);
+        assert_eq!(complete_word("exp", &words), strings(&["export-png", "export-ora"]));
+        assert_eq!(complete_word("png", &words), strings(&["png", "export-png"]));
+        assert_eq!(complete_word("zo", &words), strings(&["zoom-out"]));
+        assert_eq!(complete_word("", &words), words);
+        assert!(complete_word("xyz", &words).is_empty());
+        assert!(is_subsequence("epng", "export-png"));
+        assert!(!is_subsequence("gnpe", "export-png"));
+    }
+
+    #[test]
+    fn command_names_after_meta_x() {
+        assert_eq!(complete(&[META_X], "ex", &commands(), &[]),
+                   strings(&["export-ora", "export-png"]));
+        assert_eq!(complete(&[META_X], "", &commands(), &[]),
+                   strings(&["bind-macro", "describe-command", "export-ora", "export-png",
+                             "extract-palette", "print", "remap-to-palette", "set-color",
+                             "zoom-in"]));
+        // colors aren't completed
+        let input = [META_X, Input::Exact(String::from("set-color"))];
+        assert!(complete(&input, "re", &commands(), &[]).is_empty());
+    }
+
+    #[test]
+    fn paths_for_string_arguments() {
+        let dir = env::temp_dir().join("rusted_pixels_completion_test");
+        fs::create_dir_all(dir.join("sprites")).unwrap();
+        for name in &["sky.png", "sea.png", ".hidden.png"] {
+            fs::File::create(dir.join(name)).unwrap();
+        }
+        let dir_name = format!("{}/", dir.display());
+        let input = [META_X, Input::Exact(String::from("export-png"))];
+
+        assert_eq!(complete(&input, &format!("{}s", dir_name), &commands(), &[]),
+                   vec![format!("{}sea.png", dir_name), format!("{}sky.png", dir_name),
+                        format!("{}sprites/", dir_name)]);
+        assert_eq!(complete(&input, &format!("{}.", dir_name), &commands(), &[]),
+                   vec![format!("{}.hidden.png", dir_name)]);
+        assert!(complete(&input, &format!("{}missing/", dir_name), &commands(), &[])
+                .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn words_for_string_arguments() {
+        let entering = |name: &str| vec![META_X, Input::Exact(String::from(name))];
+        let macros = vec![
+            Macro{name: String::from("outline"), keys: None, steps: vec![]},
+            Macro{name: String::from("shade"), keys: None, steps: vec![]},
+        ];
+
+        assert_eq!(complete(&entering("describe-command"), "zoom-i", &commands(), &[])[0],
+                   "zoom-in");
+        assert_eq!(complete(&entering("extract-palette"), "l", &commands(), &[]),
+                   strings(&["luminance"]));
+        assert_eq!(complete(&entering("remap-to-palette"), "b", &commands(), &[]),
+                   strings(&["bayer", "floyd-steinberg"]));
+
+        // the macro name comes first, then the keys
+        let mut input = entering("bind-macro");
+        assert_eq!(complete(&input, "o", &commands(), &macros), strings(&["outline"]));
+        input.push(Input::String);
+        assert!(complete(&input, "o", &commands(), &macros).is_empty());
+
+        // free text isn't completed, not even as a path
+        assert!(complete(&entering("print"), "", &commands(), &macros).is_empty());
+    }
+
+    #[test]
+    fn common_prefixes() {
+        assert_eq!(common_prefix(&[]), "");
+        assert_eq!(common_prefix(&strings(&["export-png"])), "export-png");
+        assert_eq!(common_prefix(&strings(&["export-png", "export-ora"])), "export-");
+        assert_eq!(common_prefix(&strings(&["export-png", "export-ora", "zoom-in"])), "");
+        assert_eq!(common_prefix(&strings(&["zoom", "zoom-in"])), "zoom");
+        assert_eq!(common_prefix(&strings(&["äpfel", "äpple"])), "äp");
+    }
+}
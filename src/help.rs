@@ -0,0 +1,177 @@
+use input::{self, Input, Command, ArgType, InterpretErr, ALL_COMMANDS};
+use keymap;
+use state::State;
+
+/*
+ * The usage line of a command, like `generate-ramp <color> <color> <integer>`
+ */
+fn usage(command: Command) -> String {
+    let mut text = String::from(command.name());
+    for arg_type in command.signature() {
+        text = text + " " + match *arg_type {
+            ArgType::Integer => "<integer>",
+            ArgType::Color => "<color>",
+            ArgType::String => "<string>",
+        };
+    }
+    text
+}
+
+/*
+ * The keys bound to `command`, other than `M-x`
+ */
+fn keys_for(command: Command, commands: &[(Vec<Input>, Command)]) -> Vec<String> {
+    commands.iter()
+        .filter(|&&(ref inputs, bound)| bound == command && inputs[0] != input::META_X)
+        .map(|&(ref inputs, _)| show_keys(inputs))
+        .collect()
+}
+
+fn show_keys(inputs: &[Input]) -> String {
+    inputs.iter()
+        .map(|input| match *input {
+            Input::Char(..) => keymap::show_key(input),
+            Input::Exact(ref word) => word.clone(),
+            Input::Integer => String::from("<integer>"),
+            Input::Color => String::from("<color>"),
+            Input::String => String::from("<string>"),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/*
+ * Everything about a command, for the help window
+ */
+pub fn describe_command(command: Command, commands: &[(Vec<Input>, Command)]) -> Vec<String> {
+    let mut lines = vec![usage(command), String::new(), String::from(command.help())];
+    let keys = keys_for(command, commands);
+    lines.push(String::new());
+    if keys.is_empty() {
+        lines.push(format!("Run it with M-x {}", command.name()));
+    } else {
+        lines.push(format!("Bound to {}", keys.join(", ")));
+    }
+    let arguments = command.signature().len();
+    if commands.iter().any(|&(ref inputs, bound)| {
+        bound == command && inputs.iter().filter(|input| ArgType::from_input(input).is_some())
+            .count() < arguments
+    }) {
+        lines.push(String::from("Its keys leave out arguments, which then have defaults."));
+    }
+    lines
+}
+
+/*
+ * Tells what the keys read after `C-h k` or describe-key-briefly run.
+ * `brief` shows it in the status bar, otherwise it's shown in the help
+ * window.
+ */
+pub fn describe_key(state: &mut State, keys: &[Input], commands: &[(Vec<Input>, Command)],
+                    brief: bool) {
+    let shown = show_keys(keys);
+    let reads_name = commands.iter().any(|&(ref inputs, _)| {
+        inputs.len() > keys.len() && &inputs[..keys.len()] == keys
+            && match inputs[keys.len()] { Input::Exact(_) => true, _ => false }
+    });
+    if reads_name {
+        state.info(format!("{} reads the name of a command, M-x apropos lists them", shown));
+        return;
+    }
+    // a binding that runs right away, or one that then asks for input
+    let command = match input::interpret_input(keys, commands) {
+        Ok(command) => Some(command),
+        Err(InterpretErr::RequiresMoreInput) => commands.iter()
+            .find(|&&(ref inputs, _)| inputs.len() > keys.len() && &inputs[..keys.len()] == keys)
+            .map(|&(_, command)| command),
+        Err(InterpretErr::NoValidCommand) => None,
+    };
+    match command {
+        Some(Command::RunMacro(index)) => {
+            let name = state.macros[index].name.clone();
+            state.info(format!("{} runs the macro {}", shown, name));
+        },
+        Some(command) if brief => state.info(format!("{} runs {}", shown, usage(command))),
+        Some(command) => {
+            let mut lines = vec![format!("{} runs the command {}", shown, command.name()),
+                                 String::new()];
+            lines.extend(describe_command(command, commands));
+            state.show_help(lines);
+        },
+        None => state.warning(format!("{} is undefined", shown)),
+    }
+}
+
+/*
+ * One line for each command whose name or help contains `pattern`
+ */
+pub fn apropos(pattern: &str, commands: &[(Vec<Input>, Command)]) -> Vec<String> {
+    let pattern = pattern.to_lowercase();
+    let mut lines = vec![format!("Commands matching `{}`:", pattern), String::new()];
+    for &command in ALL_COMMANDS.iter() {
+        if !command.name().contains(&pattern[..])
+            && !command.help().to_lowercase().contains(&pattern[..]) {
+            continue;
+        }
+        let keys = keys_for(command, commands);
+        lines.push(if keys.is_empty() {
+            usage(command)
+        } else {
+            format!("{} ({})", usage(command), keys.join(", "))
+        });
+        lines.push(format!("    {}", command.help()));
+    }
+    if lines.len() == 2 {
+        lines.push(String::from("Nothing"));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use input::{self, Command, ALL_COMMANDS};
+    use super::{usage, apropos, describe_command};
+
+    #[test]
+    fn usages() {
+        assert_eq!(usage(Command::ZoomIn), "zoom-in");
+        assert_eq!(usage(Command::ExportPng), "export-png <string>");
+        assert_eq!(usage(Command::GenerateRamp), "generate-ramp <color> <color> <integer>");
+        assert_eq!(usage(Command::ExtractPalette), "extract-palette <string> <integer>");
+    }
+
+    #[test]
+    fn apropos_matches_names_and_help() {
+        let commands = input::get_commands();
+        let lines = apropos("ZOOM", &commands);
+        assert_eq!(lines[0], "Commands matching `zoom`:");
+        assert!(lines.contains(&String::from("zoom-in (f)")));
+        assert!(lines.contains(&String::from("zoom-out (w)")));
+        // ones without keys of their own are listed by their usage
+        let lines = apropos("ramp", &commands);
+        assert!(lines.contains(&String::from("generate-ramp <color> <color> <integer>")));
+        // matched by the help text, not the name
+        let lines = apropos("openraster", &commands);
+        assert!(lines.iter().any(|line| line.starts_with("import-ora")));
+
+        assert_eq!(apropos("no such thing", &commands),
+                   vec![String::from("Commands matching `no such thing`:"), String::new(),
+                        String::from("Nothing")]);
+        // every command shows up for an empty pattern, with its help
+        assert_eq!(apropos("", &commands).len(), 2 + 2 * ALL_COMMANDS.len());
+    }
+
+    #[test]
+    fn describing_commands() {
+        let commands = input::get_commands();
+        let lines = describe_command(Command::ExportPng, &commands);
+        assert_eq!(lines[0], "export-png <string>");
+        assert_eq!(lines[2], Command::ExportPng.help());
+        assert!(lines.contains(&String::from("Bound to C-s")));
+        assert!(lines.contains(&String::from(
+            "Its keys leave out arguments, which then have defaults.")));
+
+        let lines = describe_command(Command::GenerateRamp, &commands);
+        assert!(lines.contains(&String::from("Run it with M-x generate-ramp")));
+    }
+}
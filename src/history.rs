@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use input::ArgType;
+
+// Entries past this many are forgotten, oldest first
+const MAX_ENTRIES: usize = 100;
+
+/*
+ * What was entered in the minibuffer before. Each kind of input has its
+ * own history, so M-p while entering a color only goes through colors.
+ */
+pub struct History {
+    entries: HashMap<&'static str, Vec<String>>,
+    // How far back M-p has gone, and what was typed before it started
+    position: Option<usize>,
+    draft: String,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            entries: HashMap::new(),
+            position: None,
+            draft: String::new(),
+        }
+    }
+
+    /*
+     * The history is kept in `~/.config/rusted_pixels/history`, one
+     * `kind<TAB>entry` per line
+     */
+    pub fn path() -> Option<PathBuf> {
+        env::home_dir().map(|home| home.join(".config").join("rusted_pixels").join("history"))
+    }
+
+    pub fn load() -> io::Result<History> {
+        match History::path() {
+            Some(path) => History::load_from(&path),
+            None => Ok(History::new()),
+        }
+    }
+
+    /*
+     * A missing file is an empty history
+     */
+    pub fn load_from(path: &Path) -> io::Result<History> {
+        let mut history = History::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(error) => return Err(error),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let mut parts = line.splitn(2, '\t');
+            if let (Some(kind), Some(entry)) = (parts.next(), parts.next()) {
+                if let Some(kind) = KINDS.iter().find(|&&known| known == kind) {
+                    history.push(*kind, entry.to_string());
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    /*
+     * Written once when quitting, rather than after every entry
+     */
+    pub fn save(&self) -> io::Result<()> {
+        match History::path() {
+            Some(path) => self.save_to(&path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        let mut file = try!(File::create(path));
+        for kind in KINDS.iter() {
+            for entry in self.entries.get(kind).map(|entries| &entries[..]).unwrap_or(&[]) {
+                try!(writeln!(file, "{}\t{}", kind, entry));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, expected: Option<ArgType>, entry: &str) {
+        self.reset();
+        if entry.is_empty() || entry.contains('\n') {
+            return;
+        }
+        self.push(kind(expected), entry.to_string());
+    }
+
+    fn push(&mut self, kind: &'static str, entry: String) {
+        let entries = self.entries.entry(kind).or_insert(vec![]);
+        // only the latest use of an entry is kept
+        entries.retain(|old| *old != entry);
+        entries.push(entry);
+        if entries.len() > MAX_ENTRIES {
+            let excess = entries.len() - MAX_ENTRIES;
+            entries.drain(..excess);
+        }
+    }
+
+    /*
+     * Stops going through the history, as when the input is edited
+     */
+    pub fn reset(&mut self) {
+        self.position = None;
+    }
+
+    /*
+     * The entry before the one shown, remembering `current` so M-n can
+     * get back to it
+     */
+    pub fn previous(&mut self, expected: Option<ArgType>, current: &str) -> Option<String> {
+        let entries = match self.entries.get(kind(expected)) {
+            Some(entries) if !entries.is_empty() => entries,
+            _ => return None,
+        };
+        let position = match self.position {
+            None => {
+                self.draft = current.to_string();
+                0
+            },
+            Some(position) if position + 1 < entries.len() => position + 1,
+            Some(_) => return None,
+        };
+        self.position = Some(position);
+        Some(entries[entries.len() - 1 - position].clone())
+    }
+
+    pub fn next(&mut self, expected: Option<ArgType>) -> Option<String> {
+        match self.position {
+            None => None,
+            Some(0) => {
+                self.position = None;
+                Some(self.draft.clone())
+            },
+            Some(position) => {
+                let entries = match self.entries.get(kind(expected)) {
+                    Some(entries) => entries,
+                    None => return None,
+                };
+                self.position = Some(position - 1);
+                entries.get(entries.len() - position).cloned()
+            },
+        }
+    }
+}
+
+const KINDS: [&'static str; 4] = ["command", "integer", "color", "string"];
+
+fn kind(expected: Option<ArgType>) -> &'static str {
+    match expected {
+        None => KINDS[0],
+        Some(ArgType::Integer) => KINDS[1],
+        Some(ArgType::Color) => KINDS[2],
+        Some(ArgType::String) => KINDS[3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env,fs};
+    use input::ArgType;
+    use super::{History, MAX_ENTRIES};
+
+    #[test]
+    fn latest_use_is_kept_once() {
+        let mut history = History::new();
+        history.add(None, "zoom-in");
+        history.add(None, "export-png");
+        history.add(None, "zoom-in");
+        assert_eq!(history.previous(None, "typed"), Some(String::from("zoom-in")));
+        assert_eq!(history.previous(None, "typed"), Some(String::from("export-png")));
+        assert_eq!(history.previous(None, "typed"), None);
+        assert_eq!(history.next(None), Some(String::from("zoom-in")));
+        // back to what was typed before going through the history
+        assert_eq!(history.next(None), Some(String::from("typed")));
+        assert_eq!(history.next(None), None);
+    }
+
+    #[test]
+    fn kinds_are_separate() {
+        let mut history = History::new();
+        history.add(None, "set-color");
+        history.add(Some(ArgType::Color), "#ff0000");
+        history.add(Some(ArgType::Color), "");
+        assert_eq!(history.previous(Some(ArgType::Color), ""), Some(String::from("#ff0000")));
+        history.reset();
+        assert_eq!(history.previous(Some(ArgType::String), ""), None);
+        assert_eq!(history.previous(None, ""), Some(String::from("set-color")));
+    }
+
+    #[test]
+    fn length_is_capped() {
+        let mut history = History::new();
+        for i in 0..(MAX_ENTRIES + 10) {
+            history.add(Some(ArgType::Integer), &i.to_string());
+        }
+        let mut seen = vec![];
+        while let Some(entry) = history.previous(Some(ArgType::Integer), "") {
+            seen.push(entry);
+        }
+        assert_eq!(seen.len(), MAX_ENTRIES);
+        assert_eq!(seen.first(), Some(&(MAX_ENTRIES + 9).to_string()));
+        assert_eq!(seen.last(), Some(&String::from("10")));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join("rusted_pixels_history_test").join("history");
+        let mut history = History::new();
+        history.add(None, "export-png");
+        history.add(Some(ArgType::String), "out dir/it's.png");
+        history.add(Some(ArgType::Color), "rgb 1, 2, 3");
+        history.save_to(&path).unwrap();
+
+        let mut loaded = History::load_from(&path).unwrap();
+        assert_eq!(loaded.previous(None, ""), Some(String::from("export-png")));
+        loaded.reset();
+        assert_eq!(loaded.previous(Some(ArgType::String), ""),
+                   Some(String::from("out dir/it's.png")));
+        loaded.reset();
+        assert_eq!(loaded.previous(Some(ArgType::Color), ""), Some(String::from("rgb 1, 2, 3")));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let missing = env::temp_dir().join("rusted_pixels_history_test_missing");
+        let mut empty = History::load_from(&missing).unwrap();
+        assert_eq!(empty.previous(None, ""), None);
+    }
+}
@@ -1,16 +1,83 @@
 use png;
+use util;
 use sdl2::pixels::Color;
 use std::{fmt,fs,io,path};
 use std::error::Error;
-use std::io::Write;
+use std::io::{Read,Write};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// Larger images than this are more than anyone draws pixel by pixel, and
+// could take all the memory there is
+pub const MAX_IMAGE_SIDE_PX: usize = 4096;
+
+// How many dirty rectangles are remembered before the oldest are dropped
+const DIRTY_LOG_LENGTH: usize = 64;
+
+// Generations are shared between all images, so that an image replacing
+// another is never mistaken for an older version of it
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, Ordering::SeqCst) as u64 + 1
+}
 
 /*
  * Holds image data
+ *
+ * Every write through `get_mut_ref` or the bulk setters bumps the
+ * generation and records the modified rectangle, so that anything
+ * displaying or saving the image can ask what changed since the
+ * generation it last saw. Writing to `buffer` directly bypasses this, call
+ * `mark_dirty` afterwards.
  */
 pub struct ImageBuffer {
     pub width: usize,
     pub height: usize,
     pub buffer: Vec<Color>,
+    generation: u64,
+    // Rectangles modified in each generation, oldest first
+    dirty_log: Vec<(u64, DirtyRect)>,
+    // Changes before this generation are no longer in the log
+    forgotten_before: u64,
+}
+
+/*
+ * A rectangle of pixels in an image
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DirtyRect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        DirtyRect {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn contains(&self, other: &DirtyRect) -> bool {
+        self.x <= other.x && self.y <= other.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+
+    /*
+     * The smallest rectangle covering both
+     */
+    pub fn union(&self, other: &DirtyRect) -> DirtyRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        DirtyRect::new(x, y, right - x, bottom - y)
+    }
 }
 
 impl ImageBuffer {
@@ -21,25 +88,42 @@ impl ImageBuffer {
             buffer.push(Color::RGBA(0,0,0,0));
         }
 
+        ImageBuffer::from_buffer(width, height, buffer)
+    }
+
+    pub fn from_buffer(width: usize, height: usize, buffer: Vec<Color>) -> Self {
+        let generation = next_generation();
         ImageBuffer {
             width: width,
             height: height,
             buffer: buffer,
+            generation: generation,
+            dirty_log: vec![],
+            // anyone who hasn't seen this image yet needs all of it
+            forgotten_before: generation + 1,
         }
     }
 
     pub fn load_png_image<P: AsRef<path::Path>>(path: P) -> io::Result<Self> {
+        Self::read_png(try!(fs::File::open(path)))
+    }
+
+    /*
+     * Decodes a PNG from any reader, e.g. a file or an entry in an
+     * archive.
+     */
+    pub fn read_png<R: Read>(input: R) -> io::Result<Self> {
         use png::ColorType::*;
 
-        let decoder = png::Decoder::new(try!(fs::File::open(path)));
+        let decoder = png::Decoder::new(input);
         let (info,mut reader) = try!(decoder.read_info());
         let mut img_data = vec![0; info.buffer_size()];
         try!(reader.next_frame(&mut img_data));
 
-        Ok(ImageBuffer{
-            width: info.width as usize,
-            height: info.height as usize,
-            buffer: match info.color_type {
+        Ok(ImageBuffer::from_buffer(
+            info.width as usize,
+            info.height as usize,
+            match info.color_type {
                 RGB => {
                     //Check data length
                     if img_data.len()%3!=0{
@@ -90,29 +174,47 @@ impl ImageBuffer {
                     ));
                 }
             }
-        })
+        ))
     }
 
     pub fn save_png_image<P: AsRef<path::Path>>(&self,path: P) -> io::Result<()>{
         let mut out = Vec::new();
-        {
-            let mut encoder = png::Encoder::new(
-                &mut out,
-                png::Info{
-                    width : self.width as u32,
-                    height: self.height as u32,
-                    color_type: png::ColorType::RGB,//TODO: RGB output is hardcoded because there's no way to choose at the moment. It will fail when the color data is RGBA
-                    .. png::Info::default()
-                },
-            ).write_header().unwrap();
+        try!(self.write_png(&mut out, png::ColorType::RGBA));
 
-            //Read from the local data, convert and then write to the file data
-            let buffer: Vec<_> = self.buffer.iter().flat_map(ColorIter::new).collect();
-            encoder.write_image_data(buffer.as_ref()).unwrap();
-    }
         //Open and write to file
         let mut file = try!(fs::File::create(path));
-        try!(file.write(out.as_ref()));
+        try!(file.write_all(out.as_ref()));
+        Ok(())
+    }
+
+    /*
+     * Encodes the image as a PNG of the given color type into any writer.
+     * Only `RGB` and `RGBA` are supported.
+     */
+    pub fn write_png<W: Write>(&self, output: W, color_type: png::ColorType) -> io::Result<()>{
+        let mut encoder = try!(png::Encoder::new(
+            output,
+            png::Info{
+                width : self.width as u32,
+                height: self.height as u32,
+                color_type: color_type,
+                .. png::Info::default()
+            },
+        ).write_header());
+
+        //Read from the local data, convert and then write to the file data
+        let buffer: Vec<u8> = match color_type {
+            png::ColorType::RGB => self.buffer.iter().flat_map(|color| ColorIter::new(color).take(3)).collect(),
+            png::ColorType::RGBA => self.buffer.iter().flat_map(|color| {
+                let (r,g,b,a) = util::color_to_rgba(*color);
+                vec![r,g,b,a].into_iter()
+            }).collect(),
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                LoadImageError::UnsupportedColorType(color_type)
+            )),
+        };
+        try!(encoder.write_image_data(buffer.as_ref()));
         Ok(())
     }
 
@@ -123,8 +225,109 @@ impl ImageBuffer {
 
     #[inline(always)]
     pub fn get_mut_ref<'a>(&'a mut self, x: usize, y: usize) -> &'a mut Color {
+        self.mark_dirty(DirtyRect::new(x, y, 1, 1));
         &mut self.buffer[y* self.width + x]
     }
+
+    pub fn set_point(&mut self, x: usize, y: usize, color: Color) {
+        *self.get_mut_ref(x, y) = color;
+    }
+
+    /*
+     * Fills a rectangle, clipped to the image
+     */
+    pub fn fill_rect(&mut self, rect: DirtyRect, color: Color) {
+        let right = (rect.x + rect.width).min(self.width);
+        let bottom = (rect.y + rect.height).min(self.height);
+        if rect.x >= right || rect.y >= bottom {
+            return;
+        }
+        for y in rect.y..bottom {
+            for x in rect.x..right {
+                self.buffer[y * self.width + x] = color;
+            }
+        }
+        self.mark_dirty(DirtyRect::new(rect.x, rect.y, right - rect.x, bottom - rect.y));
+    }
+
+    /*
+     * Replaces every pixel at once, `pixels` has to be as large as the
+     * image
+     */
+    pub fn set_pixels(&mut self, pixels: Vec<Color>) {
+        assert_eq!(pixels.len(), self.buffer.len());
+        self.buffer = pixels;
+        self.mark_all_dirty();
+    }
+
+    pub fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.generation = next_generation();
+        // Consecutive writes to the same spot, like a brush stroke, are
+        // merged instead of filling the log
+        if let Some(&mut (ref mut generation, ref mut last)) = self.dirty_log.last_mut() {
+            if last.contains(&rect) || rect.contains(last) {
+                *generation = self.generation;
+                *last = last.union(&rect);
+                return;
+            }
+        }
+        if self.dirty_log.len() >= DIRTY_LOG_LENGTH {
+            let drop = DIRTY_LOG_LENGTH / 2;
+            self.forgotten_before = self.dirty_log[drop - 1].0 + 1;
+            self.dirty_log.drain(..drop);
+        }
+        self.dirty_log.push((self.generation, rect));
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        let everything = DirtyRect::new(0, 0, self.width, self.height);
+        self.mark_dirty(everything);
+    }
+
+    /*
+     * Increases with every modification, and is unique among all images
+     */
+    #[inline(always)]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /*
+     * The rectangle covering every modification made after `generation`,
+     * or `None` if nothing changed
+     */
+    pub fn dirty_since(&self, generation: u64) -> Option<DirtyRect> {
+        if generation >= self.generation {
+            return None;
+        }
+        if generation + 1 < self.forgotten_before {
+            return Some(DirtyRect::new(0, 0, self.width, self.height));
+        }
+        self.dirty_log.iter()
+            .filter(|&&(modified, _)| modified > generation)
+            .map(|&(_, rect)| rect)
+            .fold(None, |dirty: Option<DirtyRect>, rect| Some(match dirty {
+                Some(dirty) => dirty.union(&rect),
+                None => rect,
+            }))
+    }
+
+    /*
+     * The modified rectangles since `generation`, for consumers that
+     * prefer several small updates over one large one
+     */
+    pub fn dirty_rects_since(&self, generation: u64) -> Vec<DirtyRect> {
+        if generation >= self.generation {
+            return vec![];
+        }
+        if generation + 1 < self.forgotten_before {
+            return vec![DirtyRect::new(0, 0, self.width, self.height)];
+        }
+        self.dirty_log.iter()
+            .filter(|&&(modified, _)| modified > generation)
+            .map(|&(_, rect)| rect)
+            .collect()
+    }
 }
 
 /*
@@ -173,3 +376,87 @@ impl<'a> Iterator for ColorIter<'a>{
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use super::{ImageBuffer, DirtyRect, DIRTY_LOG_LENGTH};
+
+    #[test]
+    fn rect_union_and_contains() {
+        let a = DirtyRect::new(1, 1, 2, 2);
+        let b = DirtyRect::new(4, 0, 1, 1);
+        assert_eq!(a.union(&b), DirtyRect::new(1, 0, 4, 3));
+        assert_eq!(a.union(&a), a);
+        assert!(a.contains(&a));
+        assert!(a.contains(&DirtyRect::new(2, 2, 1, 1)));
+        assert!(!a.contains(&b));
+        assert!(a.union(&b).contains(&b));
+    }
+
+    #[test]
+    fn new_image_is_all_dirty() {
+        let image = ImageBuffer::new(3, 2);
+        assert_eq!(image.dirty_since(0), Some(DirtyRect::new(0, 0, 3, 2)));
+        assert_eq!(image.dirty_since(image.generation()), None);
+        assert!(image.dirty_rects_since(image.generation()).is_empty());
+    }
+
+    #[test]
+    fn generations_are_unique_and_increase() {
+        let mut first = ImageBuffer::new(2, 2);
+        let second = ImageBuffer::new(2, 2);
+        assert!(first.generation() != second.generation());
+        let before = first.generation();
+        first.set_point(0, 0, Color::RGB(1,2,3));
+        assert!(first.generation() > before);
+        assert!(first.generation() > second.generation());
+    }
+
+    #[test]
+    fn writes_are_tracked_and_merged() {
+        let mut image = ImageBuffer::new(8, 8);
+        let seen = image.generation();
+        image.set_point(1, 1, Color::RGB(1,1,1));
+        image.set_point(1, 1, Color::RGB(2,2,2));
+        // the same spot twice is one entry
+        assert_eq!(image.dirty_rects_since(seen), vec![DirtyRect::new(1, 1, 1, 1)]);
+
+        image.set_point(5, 3, Color::RGB(3,3,3));
+        assert_eq!(image.dirty_rects_since(seen),
+                   vec![DirtyRect::new(1, 1, 1, 1), DirtyRect::new(5, 3, 1, 1)]);
+        assert_eq!(image.dirty_since(seen), Some(DirtyRect::new(1, 1, 5, 3)));
+
+        let seen = image.generation();
+        image.fill_rect(DirtyRect::new(6, 6, 10, 10), Color::RGB(4,4,4));
+        assert_eq!(image.dirty_since(seen), Some(DirtyRect::new(6, 6, 2, 2)));
+        // entirely outside the image changes nothing
+        let seen = image.generation();
+        image.fill_rect(DirtyRect::new(9, 0, 2, 2), Color::RGB(4,4,4));
+        assert_eq!(image.dirty_since(seen), None);
+
+        let seen = image.generation();
+        image.set_pixels(vec![Color::RGB(0,0,0); 64]);
+        assert_eq!(image.dirty_since(seen), Some(DirtyRect::new(0, 0, 8, 8)));
+    }
+
+    #[test]
+    fn truncated_log_reports_everything() {
+        let mut image = ImageBuffer::new(DIRTY_LOG_LENGTH + 1, 1);
+        let first_write = image.generation();
+        let mut fortieth_write = 0;
+        for x in 0..(DIRTY_LOG_LENGTH + 1) {
+            image.set_point(x, 0, Color::RGB(1,1,1));
+            if x == 39 {
+                fortieth_write = image.generation();
+            }
+        }
+        assert!(image.dirty_log.len() <= DIRTY_LOG_LENGTH);
+        // the first writes were dropped, so everything has to be redone
+        assert_eq!(image.dirty_since(first_write),
+                   Some(DirtyRect::new(0, 0, DIRTY_LOG_LENGTH + 1, 1)));
+        // while recent ones are still exact
+        assert_eq!(image.dirty_since(fortieth_write),
+                   Some(DirtyRect::new(40, 0, DIRTY_LOG_LENGTH - 39, 1)));
+    }
+}
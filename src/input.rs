@@ -1,6 +1,9 @@
 use sdl2::pixels::Color;
-use sdl2::keyboard::{Keycode,Mod,LALTMOD,LCTRLMOD, NOMOD};
-use state::State;
+use sdl2::keyboard::{Keycode,Mod,LALTMOD,LCTRLMOD,LSHIFTMOD, NOMOD};
+use palette_generation;
+use state::{self, State, PrefixArg};
+use macros::Invocation;
+use help;
 use util;
 use windows::Window;
 
@@ -11,7 +14,7 @@ use windows::Window;
  * &[AltModified(char), Exact("swap-color"), Color, Color]
  */
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Input {
     Char(Keycode,Mod),
     Integer,
@@ -20,6 +23,7 @@ pub enum Input {
     Exact(String)
 }
 
+#[derive(Clone)]
 pub enum Arg {
     String(String),
     Integer(isize),
@@ -27,70 +31,681 @@ pub enum Arg {
 }
 
 impl Arg {
-    pub fn coerce_string(&self) -> String {
+    pub fn as_string(&self) -> Option<&str> {
         if let Arg::String(ref string) = *self {
-            return string.clone();
+            return Some(string);
         }
-        panic!("Commands misconfigured. Expected `String` on stack.");
+        None
     }
 
-    pub fn coerce_color(&self) -> Color {
+    pub fn as_color(&self) -> Option<Color> {
         if let Arg::Color(color) = *self {
-            return color;
+            return Some(color);
         }
-        panic!("Commands misconfigured. Expected `Color` on stack.");
+        None
     }
 
-    pub fn coerce_integer(&self) -> isize {
+    pub fn as_integer(&self) -> Option<isize> {
         if let Arg::Integer(integer) = *self {
-            return integer;
+            return Some(integer);
         }
-        panic!("Commands misconfigured. Expected `Integer` on stack.");
+        None
     }
 }
 
-#[derive(Copy, Clone)]
+/*
+ * The type of a command argument
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ArgType {
+    Integer,
+    Color,
+    String,
+}
+
+impl ArgType {
+    pub fn input(&self) -> Input {
+        match *self {
+            ArgType::Integer => Input::Integer,
+            ArgType::Color => Input::Color,
+            ArgType::String => Input::String,
+        }
+    }
+
+    pub fn from_input(input: &Input) -> Option<ArgType> {
+        match *input {
+            Input::Integer => Some(ArgType::Integer),
+            Input::Color => Some(ArgType::Color),
+            Input::String => Some(ArgType::String),
+            Input::Char(..) | Input::Exact(_) => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            ArgType::Integer => "an integer",
+            ArgType::Color => "a color, like #ff8000, rgb 255,128,0 or p3",
+            ArgType::String => "a string, like 'out.png'",
+        }
+    }
+}
+
+/*
+ * Arguments are validated as they're entered, so these only fail if a
+ * command doesn't take what its bindings say.
+ */
+pub fn pop_string(args: &mut Vec<Arg>) -> Result<String, String> {
+    match args.pop() {
+        Some(Arg::String(string)) => Ok(string),
+        _ => Err(String::from("Commands misconfigured. Expected `String` on stack.")),
+    }
+}
+
+pub fn pop_color(args: &mut Vec<Arg>) -> Result<Color, String> {
+    match args.pop() {
+        Some(Arg::Color(color)) => Ok(color),
+        _ => Err(String::from("Commands misconfigured. Expected `Color` on stack.")),
+    }
+}
+
+pub fn pop_integer(args: &mut Vec<Arg>) -> Result<isize, String> {
+    match args.pop() {
+        Some(Arg::Integer(integer)) => Ok(integer),
+        _ => Err(String::from("Commands misconfigured. Expected `Integer` on stack.")),
+    }
+}
+
+/*
+ * Like `try!`, but for functions returning a `CommandResult`
+ */
+macro_rules! try_arg {
+    ($expr:expr) => (match $expr {
+        Ok(value) => value,
+        Err(message) => return CommandResult::Error(message),
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Command {
+    ActualSize,
+    AddColor,
+    Apropos,
+    BindMacro,
+    CallLastMacro,
+    CursorDown,
+    CursorLeft,
+    CursorRight,
+    CursorUp,
+    DeletePalette,
+    DescribeCommand,
+    DescribeKey,
+    DescribeKeyBriefly,
+    DuplicatePalette,
+    DrawLine,
+    DrawRectangle,
+    EndMacro,
+    ExportOra,
     ExportPng,
+    ExtractPalette,
+    Fill,
+    FitToWindow,
+    GenerateComplementary,
+    GenerateHsvRamp,
+    GenerateOklabRamp,
+    GenerateRamp,
+    GenerateShading,
+    GenerateTriadic,
+    ImportOra,
+    LoadMacros,
+    LoadPng,
+    LoadPalette,
+    MoveColor,
+    NameLastMacro,
+    NewImage,
+    NewPalette,
+    NextPalette,
+    PickColor,
+    Plot,
+    PanDown,
+    PanLeft,
+    PanRight,
+    PanUp,
+    PreviousPalette,
     Print,
     Quit,
+    ReloadConfig,
+    RemapToPalette,
+    RemoveColor,
+    RenamePalette,
+    ReplaceColor,
+    // A named macro, by its index in `State::macros`
+    RunMacro(usize),
+    SaveMacros,
+    SavePalette,
     SetColor,
+    SetCursor,
+    SetMark,
+    SetPixelGridColor,
+    SetTileGrid,
+    SetTileGridColor,
+    SetTileGridOffset,
+    StartMacro,
+    TogglePaintMode,
+    TogglePixelGrid,
+    ToggleTileGrid,
     ZoomIn,
     ZoomOut,
+    UniversalArgument,
     Undo,
 }
 
-const META_X: Input = Input::Char(Keycode::X,LALTMOD);
+impl Command {
+    /*
+     * The arguments the command takes, in order. A binding may leave out
+     * trailing arguments, which the command then defaults.
+     */
+    pub fn signature(&self) -> &'static [ArgType] {
+        use self::ArgType::*;
+        match *self {
+            Command::ExportOra | Command::ExportPng | Command::ImportOra
+                | Command::LoadPalette | Command::NewPalette | Command::Print
+                | Command::RemapToPalette | Command::RenamePalette
+                | Command::SavePalette | Command::LoadMacros | Command::NameLastMacro
+                | Command::SaveMacros | Command::Apropos | Command::LoadPng
+                | Command::DescribeCommand => &[String],
+            Command::BindMacro => &[String, String],
+            Command::ExtractPalette => &[String, Integer],
+            Command::GenerateComplementary | Command::GenerateTriadic
+                | Command::SetColor | Command::SetPixelGridColor
+                | Command::SetTileGridColor => &[Color],
+            Command::GenerateHsvRamp | Command::GenerateOklabRamp
+                | Command::GenerateRamp => &[Color, Color, Integer],
+            Command::GenerateShading => &[Color, Integer, Integer, Integer],
+            Command::MoveColor | Command::SetTileGrid
+                | Command::SetTileGridOffset | Command::SetCursor
+                | Command::NewImage => &[Integer, Integer],
+            // where the cursor is, unless given
+            Command::Fill | Command::PickColor | Command::Plot => &[Integer, Integer],
+            Command::RemoveColor | Command::ReplaceColor => &[Integer],
+            Command::CallLastMacro | Command::EndMacro | Command::RunMacro(_)
+                | Command::StartMacro => &[],
+            Command::CursorDown | Command::CursorLeft | Command::CursorRight
+                | Command::CursorUp | Command::DrawLine | Command::DrawRectangle
+                | Command::SetMark | Command::DescribeKey
+                | Command::DescribeKeyBriefly => &[],
+            Command::ActualSize | Command::AddColor | Command::DeletePalette
+                | Command::DuplicatePalette | Command::FitToWindow
+                | Command::NextPalette | Command::PanDown | Command::PanLeft
+                | Command::PanRight | Command::PanUp | Command::PreviousPalette
+                | Command::Quit | Command::ReloadConfig | Command::TogglePaintMode
+                | Command::TogglePixelGrid | Command::ToggleTileGrid
+                | Command::UniversalArgument | Command::ZoomIn | Command::ZoomOut
+                | Command::Undo => &[],
+        }
+    }
+
+    /*
+     * The name the command has after `M-x` and in the key bindings file
+     */
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::ActualSize => "actual-size",
+            Command::AddColor => "add-color",
+            Command::Apropos => "apropos",
+            Command::BindMacro => "bind-macro",
+            Command::CallLastMacro => "call-last-macro",
+            Command::CursorDown => "cursor-down",
+            Command::CursorLeft => "cursor-left",
+            Command::CursorRight => "cursor-right",
+            Command::CursorUp => "cursor-up",
+            Command::DeletePalette => "delete-palette",
+            Command::DescribeCommand => "describe-command",
+            Command::DescribeKey => "describe-key",
+            Command::DescribeKeyBriefly => "describe-key-briefly",
+            Command::DuplicatePalette => "duplicate-palette",
+            Command::DrawLine => "draw-line",
+            Command::DrawRectangle => "draw-rectangle",
+            Command::EndMacro => "end-macro",
+            Command::ExportOra => "export-ora",
+            Command::ExportPng => "export-png",
+            Command::ExtractPalette => "extract-palette",
+            Command::Fill => "fill",
+            Command::FitToWindow => "fit-to-window",
+            Command::GenerateComplementary => "generate-complementary",
+            Command::GenerateHsvRamp => "generate-hsv-ramp",
+            Command::GenerateOklabRamp => "generate-oklab-ramp",
+            Command::GenerateRamp => "generate-ramp",
+            Command::GenerateShading => "generate-shading",
+            Command::GenerateTriadic => "generate-triadic",
+            Command::ImportOra => "import-ora",
+            Command::LoadMacros => "load-macros",
+            Command::LoadPng => "load-png",
+            Command::LoadPalette => "load-palette",
+            Command::MoveColor => "move-color",
+            Command::NameLastMacro => "name-last-macro",
+            Command::NewImage => "new-image",
+            Command::NewPalette => "new-palette",
+            Command::NextPalette => "next-palette",
+            Command::PickColor => "pick-color",
+            Command::Plot => "plot",
+            Command::PanDown => "pan-down",
+            Command::PanLeft => "pan-left",
+            Command::PanRight => "pan-right",
+            Command::PanUp => "pan-up",
+            Command::PreviousPalette => "previous-palette",
+            Command::Print => "print",
+            Command::Quit => "quit",
+            Command::ReloadConfig => "reload-config",
+            Command::RemapToPalette => "remap-to-palette",
+            Command::RemoveColor => "remove-color",
+            Command::RenamePalette => "rename-palette",
+            Command::ReplaceColor => "replace-color",
+            Command::RunMacro(_) => "run-macro",
+            Command::SaveMacros => "save-macros",
+            Command::SavePalette => "save-palette",
+            Command::SetColor => "set-color",
+            Command::SetCursor => "set-cursor",
+            Command::SetMark => "set-mark",
+            Command::SetPixelGridColor => "set-pixel-grid-color",
+            Command::SetTileGrid => "set-tile-grid",
+            Command::SetTileGridColor => "set-tile-grid-color",
+            Command::SetTileGridOffset => "set-tile-grid-offset",
+            Command::StartMacro => "start-macro",
+            Command::TogglePaintMode => "toggle-replace-mode",
+            Command::TogglePixelGrid => "toggle-pixel-grid",
+            Command::ToggleTileGrid => "toggle-tile-grid",
+            Command::ZoomIn => "zoom-in",
+            Command::ZoomOut => "zoom-out",
+            Command::UniversalArgument => "universal-argument",
+            Command::Undo => "undo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Command> {
+        ALL_COMMANDS.iter().find(|command| command.name() == name).cloned()
+    }
+
+    /*
+     * What the command does, for the help window
+     */
+    pub fn help(&self) -> &'static str {
+        match *self {
+            Command::ActualSize =>
+                "Shows the image at 1x zoom.",
+            Command::AddColor =>
+                "Adds the current color to the end of the current palette.",
+            Command::Apropos =>
+                "Lists the commands whose name or help contains the given text.",
+            Command::BindMacro =>
+                "Binds the keys given second, like `C-c o`, to the named macro given first.",
+            Command::CallLastMacro =>
+                "Runs the last recorded macro, as many times as the count says.",
+            Command::CursorDown =>
+                "Moves the canvas cursor down, by the count if one is given.",
+            Command::CursorLeft =>
+                "Moves the canvas cursor left, by the count if one is given.",
+            Command::CursorRight =>
+                "Moves the canvas cursor right, by the count if one is given.",
+            Command::CursorUp =>
+                "Moves the canvas cursor up, by the count if one is given.",
+            Command::DeletePalette =>
+                "Deletes the current palette, unless it's the only one.",
+            Command::DescribeCommand =>
+                "Shows the arguments, keys and help of the command with the given name.",
+            Command::DescribeKey =>
+                "Reads a key sequence and shows the command it runs, with its arguments and help.",
+            Command::DescribeKeyBriefly =>
+                "Reads a key sequence and shows the command it runs in the status bar.",
+            Command::DuplicatePalette =>
+                "Adds a copy of the current palette and makes it current.",
+            Command::DrawLine =>
+                "Draws a line from the mark to the canvas cursor, then moves the mark to the cursor.",
+            Command::DrawRectangle =>
+                "Draws the outline of the rectangle between the mark and the canvas cursor.",
+            Command::EndMacro =>
+                "Stops recording, making what was recorded the last macro.",
+            Command::ExportOra =>
+                "Saves the layers as an OpenRaster file.",
+            Command::ExportPng =>
+                "Saves the image as a PNG, to tmp/test_out.png unless a path is given.",
+            Command::ExtractPalette =>
+                "Makes a palette of up to the given number of colors from the image, ordered by usage, hue or luminance.",
+            Command::Fill =>
+                "Flood fills the area under the canvas cursor, or at the given point, with the current color.",
+            Command::FitToWindow =>
+                "Zooms the image to the largest scale that fits the window.",
+            Command::GenerateComplementary =>
+                "Adds the color and its complement to the current palette.",
+            Command::GenerateHsvRamp =>
+                "Adds a ramp between two colors, interpolated in HSV, with the given number of steps.",
+            Command::GenerateOklabRamp =>
+                "Adds a ramp between two colors, interpolated in OKLab, with the given number of steps.",
+            Command::GenerateRamp =>
+                "Adds a ramp between two colors, interpolated in RGB, with the given number of steps.",
+            Command::GenerateShading =>
+                "Adds shades of a color: steps, hue shift in degrees and saturation curve in percent.",
+            Command::GenerateTriadic =>
+                "Adds the color and the two colors a third of the hue circle away.",
+            Command::ImportOra =>
+                "Loads the layers of an OpenRaster file.",
+            Command::LoadMacros =>
+                "Adds the macros in a file saved with save-macros.",
+            Command::LoadPng =>
+                "Replaces the image in the active window with a PNG file.",
+            Command::LoadPalette =>
+                "Loads a GPL, JASC, HEX, ACT or PNG palette and makes it current.",
+            Command::MoveColor =>
+                "Moves the palette color at the first index to the second.",
+            Command::NameLastMacro =>
+                "Names the last macro, so `M-x name` runs it.",
+            Command::NewImage =>
+                "Replaces the image in the active window with a transparent one of the given width and height.",
+            Command::NewPalette =>
+                "Adds an empty palette with the given name and makes it current.",
+            Command::NextPalette =>
+                "Makes the next palette current.",
+            Command::PickColor =>
+                "Makes the color under the canvas cursor, or at the given point, the current color.",
+            Command::Plot =>
+                "Paints the pixel under the canvas cursor, or at the given point.",
+            Command::PanDown =>
+                "Moves the view down.",
+            Command::PanLeft =>
+                "Moves the view left.",
+            Command::PanRight =>
+                "Moves the view right.",
+            Command::PanUp =>
+                "Moves the view up.",
+            Command::PreviousPalette =>
+                "Makes the previous palette current.",
+            Command::Print =>
+                "Shows the given text in the status bar.",
+            Command::Quit =>
+                "Quits the editor.",
+            Command::ReloadConfig =>
+                "Reads the key bindings file again.",
+            Command::RemapToPalette =>
+                "Changes every pixel to its nearest palette color, dithering with none, floyd-steinberg, atkinson or bayer.",
+            Command::RemoveColor =>
+                "Removes the palette color at the given index.",
+            Command::RenamePalette =>
+                "Renames the current palette.",
+            Command::ReplaceColor =>
+                "Sets the palette color at the given index to the current color.",
+            Command::RunMacro(_) =>
+                "Runs a named macro.",
+            Command::SaveMacros =>
+                "Saves the named macros to a file.",
+            Command::SavePalette =>
+                "Saves the current palette, in the format its extension names.",
+            Command::SetColor =>
+                "Sets the current color.",
+            Command::SetCursor =>
+                "Moves the canvas cursor to the given point.",
+            Command::SetMark =>
+                "Sets the mark at the canvas cursor, where lines and rectangles start.",
+            Command::SetPixelGridColor =>
+                "Sets the color of the pixel grid.",
+            Command::SetTileGrid =>
+                "Sets the width and height of the tiles in the tile grid.",
+            Command::SetTileGridColor =>
+                "Sets the color of the tile grid.",
+            Command::SetTileGridOffset =>
+                "Sets where the tile grid starts, in pixels.",
+            Command::StartMacro =>
+                "Starts recording the commands that run into a macro.",
+            Command::TogglePaintMode =>
+                "Switches between blending the current color over pixels and replacing them.",
+            Command::TogglePixelGrid =>
+                "Shows or hides the pixel grid.",
+            Command::ToggleTileGrid =>
+                "Shows or hides the tile grid.",
+            Command::ZoomIn =>
+                "Zooms in, by the count if one is given.",
+            Command::ZoomOut =>
+                "Zooms out, by the count if one is given.",
+            Command::UniversalArgument =>
+                "Starts a count for the next command, four times larger each time it's pressed.",
+            Command::Undo =>
+                "Undoes the last change, as many times as the count says.",
+        }
+    }
+}
+
+/*
+ * Every command with a name of its own, which leaves out `RunMacro`
+ */
+pub const ALL_COMMANDS: [Command; 68] = [
+    Command::ActualSize,
+    Command::AddColor,
+    Command::Apropos,
+    Command::BindMacro,
+    Command::CallLastMacro,
+    Command::CursorDown,
+    Command::CursorLeft,
+    Command::CursorRight,
+    Command::CursorUp,
+    Command::DeletePalette,
+    Command::DescribeCommand,
+    Command::DescribeKey,
+    Command::DescribeKeyBriefly,
+    Command::DuplicatePalette,
+    Command::DrawLine,
+    Command::DrawRectangle,
+    Command::EndMacro,
+    Command::ExportOra,
+    Command::ExportPng,
+    Command::ExtractPalette,
+    Command::Fill,
+    Command::FitToWindow,
+    Command::GenerateComplementary,
+    Command::GenerateHsvRamp,
+    Command::GenerateOklabRamp,
+    Command::GenerateRamp,
+    Command::GenerateShading,
+    Command::GenerateTriadic,
+    Command::ImportOra,
+    Command::LoadMacros,
+    Command::LoadPng,
+    Command::LoadPalette,
+    Command::MoveColor,
+    Command::NameLastMacro,
+    Command::NewImage,
+    Command::NewPalette,
+    Command::NextPalette,
+    Command::PickColor,
+    Command::Plot,
+    Command::PanDown,
+    Command::PanLeft,
+    Command::PanRight,
+    Command::PanUp,
+    Command::PreviousPalette,
+    Command::Print,
+    Command::Quit,
+    Command::ReloadConfig,
+    Command::RemapToPalette,
+    Command::RemoveColor,
+    Command::RenamePalette,
+    Command::ReplaceColor,
+    Command::SaveMacros,
+    Command::SavePalette,
+    Command::SetColor,
+    Command::SetCursor,
+    Command::SetMark,
+    Command::SetPixelGridColor,
+    Command::SetTileGrid,
+    Command::SetTileGridColor,
+    Command::SetTileGridOffset,
+    Command::StartMacro,
+    Command::TogglePaintMode,
+    Command::TogglePixelGrid,
+    Command::ToggleTileGrid,
+    Command::ZoomIn,
+    Command::ZoomOut,
+    Command::UniversalArgument,
+    Command::Undo,
+];
+
+pub const META_X: Input = Input::Char(Keycode::X,LALTMOD);
+// How far h/j/k/l pan, in screen pixels
+const PAN_STEP_PX: i32 = 32;
 
+/*
+ * How far `steps` pans, which fits an `i32` since counts are capped
+ */
+fn pan_distance(steps: isize) -> i32 {
+    (PAN_STEP_PX as isize * state::clamp_count(steps)) as i32
+}
+
+/*
+ * Every command can be run as `M-x name` followed by its arguments. Some
+ * also have keys of their own.
+ */
 pub fn get_commands() -> Vec<(Vec<Input>, Command)> {
+    let mut commands = default_keys();
+    for &command in ALL_COMMANDS.iter() {
+        let mut inputs = vec![META_X, Input::Exact(String::from(command.name()))];
+        inputs.extend(command.signature().iter().map(ArgType::input));
+        commands.push((inputs, command));
+    }
+    commands
+}
+
+fn default_keys() -> Vec<(Vec<Input>, Command)> {
     vec![(vec![Input::Char(Keycode::S,LCTRLMOD)],
           Command::ExportPng),
-         (vec![META_X,
-               Input::Exact(String::from("export-png")),
-               Input::String],
-          Command::ExportPng),
+         (vec![Input::Char(Keycode::RightBracket, NOMOD)],
+          Command::NextPalette),
+         (vec![Input::Char(Keycode::LeftBracket, NOMOD)],
+          Command::PreviousPalette),
          (vec![Input::Char(Keycode::Q,LCTRLMOD)],
           Command::Quit),
-         (vec![META_X,
-               Input::Exact(String::from("quit"))],
-          Command::Quit),
-         (vec![META_X,
-               Input::Exact(String::from("print")),
-               Input::String],
-          Command::Print),
-         (vec![META_X,
-               Input::Exact(String::from("set-color")),
-               Input::Color],
-          Command::SetColor),
+         (vec![Input::Char(Keycode::G, NOMOD)],
+          Command::TogglePixelGrid),
+         (vec![Input::Char(Keycode::T, NOMOD)],
+          Command::ToggleTileGrid),
          (vec![Input::Char(Keycode::W, NOMOD)],
           Command::ZoomOut),
          (vec![Input::Char(Keycode::F, NOMOD)],
           Command::ZoomIn),
          (vec![Input::Char(Keycode::Z, LCTRLMOD)],
           Command::Undo),
+         (vec![Input::Char(Keycode::U, LCTRLMOD)],
+          Command::UniversalArgument),
+         (vec![Input::Char(Keycode::X, LCTRLMOD), Input::Char(Keycode::Num9, LSHIFTMOD)],
+          Command::StartMacro),
+         (vec![Input::Char(Keycode::X, LCTRLMOD), Input::Char(Keycode::Num0, LSHIFTMOD)],
+          Command::EndMacro),
+         (vec![Input::Char(Keycode::X, LCTRLMOD), Input::Char(Keycode::E, NOMOD)],
+          Command::CallLastMacro),
+         (vec![Input::Char(Keycode::Left, NOMOD)],
+          Command::CursorLeft),
+         (vec![Input::Char(Keycode::Right, NOMOD)],
+          Command::CursorRight),
+         (vec![Input::Char(Keycode::Up, NOMOD)],
+          Command::CursorUp),
+         (vec![Input::Char(Keycode::Down, NOMOD)],
+          Command::CursorDown),
+         (vec![Input::Char(Keycode::Space, NOMOD)],
+          Command::Plot),
+         (vec![Input::Char(Keycode::Space, LCTRLMOD)],
+          Command::SetMark),
+         (vec![Input::Char(Keycode::D, NOMOD)],
+          Command::DrawLine),
+         (vec![Input::Char(Keycode::R, NOMOD)],
+          Command::DrawRectangle),
+         (vec![Input::Char(Keycode::B, NOMOD)],
+          Command::Fill),
+         (vec![Input::Char(Keycode::I, NOMOD)],
+          Command::PickColor),
+         (vec![Input::Char(Keycode::H, LCTRLMOD), Input::Char(Keycode::K, NOMOD)],
+          Command::DescribeKey),
+         (vec![Input::Char(Keycode::H, LCTRLMOD), Input::Char(Keycode::C, NOMOD), Input::String],
+          Command::DescribeCommand),
+         (vec![Input::Char(Keycode::H, NOMOD)],
+          Command::PanLeft),
+         (vec![Input::Char(Keycode::J, NOMOD)],
+          Command::PanDown),
+         (vec![Input::Char(Keycode::K, NOMOD)],
+          Command::PanUp),
+         (vec![Input::Char(Keycode::L, NOMOD)],
+          Command::PanRight),
+         (vec![Input::Char(Keycode::Equals, NOMOD)],
+          Command::FitToWindow),
+         (vec![Input::Char(Keycode::Equals, LCTRLMOD)],
+          Command::ActualSize),
     ]
 }
 
+/*
+ * Checks that the arguments of every binding agree with the signature of
+ * its command
+ */
+pub fn check_bindings(commands: &[(Vec<Input>, Command)]) -> Result<(), String> {
+    for &(ref inputs, command) in commands {
+        let args: Vec<ArgType> = inputs.iter().filter_map(ArgType::from_input).collect();
+        let signature = command.signature();
+        if args.len() > signature.len() || &signature[..args.len()] != &args[..] {
+            return Err(format!("binding for {:?} takes {:?}, but the command takes {:?}",
+                               command, args, signature));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * The argument the bindings matching `input` so far expect next, or `None`
+ * if they expect a key or a word
+ */
+pub fn expected_arg(input: &[Input], commands: &[(Vec<Input>, Command)]) -> Option<ArgType> {
+    commands.iter()
+        .filter(|&&(ref inputstack, _)| {
+            input.len() < inputstack.len() && input == &inputstack[0..input.len()]
+        })
+        .filter_map(|&(ref inputstack, _)| ArgType::from_input(&inputstack[input.len()]))
+        .next()
+}
+
+/*
+ * Whether the bindings matching `input` so far continue with a key, like
+ * after the `C-x` of `C-x C-s`
+ */
+pub fn expects_key(input: &[Input], commands: &[(Vec<Input>, Command)]) -> bool {
+    commands.iter().any(|&(ref inputstack, _)| {
+        input.len() < inputstack.len() && input == &inputstack[0..input.len()]
+            && match inputstack[input.len()] { Input::Char(..) => true, _ => false }
+    })
+}
+
+/*
+ * Whether any binding starts with `input`
+ */
+pub fn is_bound(input: &[Input], commands: &[(Vec<Input>, Command)]) -> bool {
+    match interpret_input(input, commands) {
+        Err(InterpretErr::NoValidCommand) => false,
+        _ => true,
+    }
+}
+
+/*
+ * The digit on a number key, for counts like `4 f`
+ */
+pub fn digit_of(keycode: Keycode) -> Option<isize> {
+    match keycode {
+        Keycode::Num0 | Keycode::Kp0 => Some(0),
+        Keycode::Num1 | Keycode::Kp1 => Some(1),
+        Keycode::Num2 | Keycode::Kp2 => Some(2),
+        Keycode::Num3 | Keycode::Kp3 => Some(3),
+        Keycode::Num4 | Keycode::Kp4 => Some(4),
+        Keycode::Num5 | Keycode::Kp5 => Some(5),
+        Keycode::Num6 | Keycode::Kp6 => Some(6),
+        Keycode::Num7 | Keycode::Kp7 => Some(7),
+        Keycode::Num8 | Keycode::Kp8 => Some(8),
+        Keycode::Num9 | Keycode::Kp9 => Some(9),
+        _ => None,
+    }
+}
+
 pub enum InterpretErr {
     NoValidCommand,
     RequiresMoreInput
@@ -122,9 +737,15 @@ pub fn interpret_input(input: &[Input],
 
 pub enum CommandResult {
     Quit,
+    // The key bindings should be read again, which only main can do
+    ReloadConfig,
+    // The macros were named or bound, so their bindings need updating
+    BindingsChanged,
     RequiresMoreInput,
     NoValidCommand,
     Success,
+    // The command failed, the input is dropped and the message shown
+    Error(String),
 }
 
 /*
@@ -143,16 +764,42 @@ pub fn execute_command(state: &mut State, windows: &mut [Box<Window>],
         
     match interpret_input(&state.input, commands) {
         Ok(command) =>  {
-            match select_command(state, windows, command) {
+            // a count is used up by the command it's given to
+            let count = match command {
+                Command::UniversalArgument => None,
+                _ => state.prefix_arg.take().map(|prefix| prefix.count()),
+            };
+            let args = state.args.clone();
+            let result = select_command(state, windows, commands, command, count);
+            if let CommandResult::Success = result {
+                record(state, command, args, count);
+            }
+            match result {
                 CommandResult::Success => {
                     clean_input_and_args(state);
                     CommandResult::Success
                 },
+                CommandResult::Error(message) => {
+                    state.error(message.clone());
+                    clean_input_and_args(state);
+                    CommandResult::Error(message)
+                },
+                CommandResult::ReloadConfig => {
+                    clean_input_and_args(state);
+                    CommandResult::ReloadConfig
+                },
+                CommandResult::BindingsChanged => {
+                    clean_input_and_args(state);
+                    CommandResult::BindingsChanged
+                },
                 anything_else => anything_else
             }
         },
         Err(InterpretErr::NoValidCommand) => {
+            let undefined = state.show_input_stack();
+            state.warning(format!("{} is undefined", undefined.trim()));
             clean_input_and_args(state);
+            state.prefix_arg = None;
             CommandResult::NoValidCommand
         },
         Err(InterpretErr::RequiresMoreInput) => {
@@ -161,31 +808,326 @@ pub fn execute_command(state: &mut State, windows: &mut [Box<Window>],
     }
 }
 
-fn select_command(state: &mut State, windows: &mut [Box<Window>], command: Command) -> CommandResult {
+/*
+ * Adds a command that ran to the macro being recorded. Named macros are
+ * recorded as their steps, so a macro never ends up running itself.
+ */
+fn record(state: &mut State, command: Command, args: Vec<Arg>, count: Option<isize>) {
+    let steps = match command {
+        Command::StartMacro | Command::EndMacro | Command::CallLastMacro
+            | Command::UniversalArgument => return,
+        Command::RunMacro(index) => state.macros[index].steps.clone(),
+        _ => vec![Invocation { command: command, args: args, count: count }],
+    };
+    if let Some(ref mut recording) = state.recording {
+        recording.extend(steps);
+    }
+}
+
+/*
+ * Runs `steps` `times` times over, stopping at the first error
+ */
+fn run_macro(state: &mut State, windows: &mut [Box<Window>],
+             commands: &[(Vec<Input>, Command)], steps: &[Invocation],
+             times: isize) -> CommandResult {
+    for _ in 0..times {
+        for step in steps {
+            state.args = step.args.clone();
+            match select_command(state, windows, commands, step.command, step.count) {
+                CommandResult::Success => {},
+                CommandResult::Error(message) =>
+                    return CommandResult::Error(format!("macro stopped at `{}`: {}",
+                                                        step.to_line(), message)),
+                _ => return CommandResult::Error(format!("`{}` can't run in a macro",
+                                                         step.to_line())),
+            }
+        }
+    }
+    state.args.clear();
+    CommandResult::Success
+}
+
+/*
+ * The image of the active window, for commands that draw on it
+ */
+fn active_image(state: &State, windows: &[Box<Window>]) -> Result<usize, String> {
+    match windows[state.window_index].image_id() {
+        Some(image_id) if image_id < state.images.len() => Ok(image_id),
+        Some(image_id) => Err(format!("no image {}", image_id)),
+        None => Err(String::from("the active window has no image")),
+    }
+}
+
+/*
+ * `count` is the prefix argument, if one was given
+ */
+fn select_command(state: &mut State, windows: &mut [Box<Window>],
+                  commands: &[(Vec<Input>, Command)], command: Command,
+                  count: Option<isize>) -> CommandResult {
+    // counts from macro files haven't been through `PrefixArg::count`
+    let steps = state::clamp_count(count.unwrap_or(1));
     match command {
+        Command::ActualSize => {
+            windows[state.window_index].actual_size();
+            CommandResult::Success
+        },
+        Command::AddColor => {
+            commands::add_color(state)
+        },
+        Command::DeletePalette => {
+            commands::delete_palette(state)
+        },
+        Command::DuplicatePalette => {
+            commands::duplicate_palette(state)
+        },
+        Command::ExportOra => {
+            commands::export_ora(state)
+        },
         Command::ExportPng => {
             commands::export_png(state)
         },
+        Command::LoadPng => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::load_png(state, image_id)
+        },
+        Command::NewImage => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::new_image(state, image_id)
+        },
+        Command::ExtractPalette => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::extract_palette(state, image_id)
+        },
+        Command::FitToWindow => {
+            windows[state.window_index].fit_to_window(state);
+            CommandResult::Success
+        },
+        Command::GenerateComplementary => {
+            commands::generate_complementary(state)
+        },
+        Command::GenerateHsvRamp => {
+            commands::generate_ramp(state, palette_generation::hsv_ramp, "hsv ramp")
+        },
+        Command::GenerateOklabRamp => {
+            commands::generate_ramp(state, palette_generation::oklab_ramp, "oklab ramp")
+        },
+        Command::GenerateRamp => {
+            commands::generate_ramp(state, palette_generation::linear_ramp, "ramp")
+        },
+        Command::GenerateShading => {
+            commands::generate_shading(state)
+        },
+        Command::GenerateTriadic => {
+            commands::generate_triadic(state)
+        },
+        Command::ImportOra => {
+            let images_needed = windows.iter()
+                .filter_map(|window| window.image_id())
+                .max()
+                .map_or(1, |image_id| image_id + 1);
+            commands::import_ora(state, images_needed)
+        },
+        Command::LoadPalette => {
+            commands::load_palette(state)
+        },
+        Command::SavePalette => {
+            commands::save_palette(state)
+        },
+        Command::MoveColor => {
+            commands::move_color(state)
+        },
+        Command::NewPalette => {
+            commands::new_palette(state)
+        },
+        Command::NextPalette => {
+            commands::cycle_palette(state, steps)
+        },
+        Command::PanDown => {
+            windows[state.window_index].pan(0, -pan_distance(steps));
+            CommandResult::Success
+        },
+        Command::PanLeft => {
+            windows[state.window_index].pan(pan_distance(steps), 0);
+            CommandResult::Success
+        },
+        Command::PanRight => {
+            windows[state.window_index].pan(-pan_distance(steps), 0);
+            CommandResult::Success
+        },
+        Command::PanUp => {
+            windows[state.window_index].pan(0, pan_distance(steps));
+            CommandResult::Success
+        },
+        Command::PreviousPalette => {
+            commands::cycle_palette(state, -steps)
+        },
+        Command::RemapToPalette => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::remap_to_palette(state, image_id)
+        },
+        Command::RemoveColor => {
+            commands::remove_color(state)
+        },
+        Command::RenamePalette => {
+            commands::rename_palette(state)
+        },
+        Command::ReplaceColor => {
+            commands::replace_color(state)
+        },
+        Command::ReloadConfig => {
+            CommandResult::ReloadConfig
+        },
         Command::Quit => {
-            println!("quit succesfully");
             CommandResult::Quit
-            },
+        },
         Command::Print => {
             commands::print(state)
         },
         Command::SetColor => {
             commands::set_color(state)
         },
+        Command::TogglePaintMode => {
+            commands::toggle_paint_mode(state)
+        },
+        Command::TogglePixelGrid => {
+            state.grid.show_pixel_grid = !state.grid.show_pixel_grid;
+            CommandResult::Success
+        },
+        Command::ToggleTileGrid => {
+            state.grid.show_tile_grid = !state.grid.show_tile_grid;
+            CommandResult::Success
+        },
+        Command::SetPixelGridColor => {
+            state.grid.pixel_grid_color = try_arg!(pop_color(&mut state.args));
+            CommandResult::Success
+        },
+        Command::SetTileGrid => {
+            commands::set_tile_grid(state)
+        },
+        Command::SetTileGridColor => {
+            state.grid.tile_grid_color = try_arg!(pop_color(&mut state.args));
+            CommandResult::Success
+        },
+        Command::SetTileGridOffset => {
+            commands::set_tile_grid_offset(state)
+        },
+        Command::CursorDown | Command::CursorLeft | Command::CursorRight | Command::CursorUp => {
+            let image_id = try_arg!(active_image(state, windows));
+            let (dx, dy) = match command {
+                Command::CursorDown => (0, steps),
+                Command::CursorLeft => (-steps, 0),
+                Command::CursorRight => (steps, 0),
+                _ => (0, -steps),
+            };
+            commands::move_cursor(state, image_id, dx, dy)
+        },
+        Command::SetCursor => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::set_cursor(state, image_id)
+        },
+        Command::SetMark => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::set_mark(state, image_id)
+        },
+        Command::Plot => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::plot(state, image_id)
+        },
+        Command::DrawLine => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::draw_line(state, image_id)
+        },
+        Command::DrawRectangle => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::draw_rectangle(state, image_id)
+        },
+        Command::Fill => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::fill(state, image_id)
+        },
+        Command::PickColor => {
+            let image_id = try_arg!(active_image(state, windows));
+            commands::pick_color(state, image_id)
+        },
+        Command::DescribeKey => {
+            state.describing_key = Some(false);
+            state.info("Describe key:");
+            CommandResult::Success
+        },
+        Command::DescribeKeyBriefly => {
+            state.describing_key = Some(true);
+            state.info("Describe key briefly:");
+            CommandResult::Success
+        },
+        Command::DescribeCommand => {
+            let name = try_arg!(pop_string(&mut state.args));
+            match Command::from_name(&name) {
+                Some(described) => {
+                    let lines = help::describe_command(described, commands);
+                    state.show_help(lines);
+                    CommandResult::Success
+                },
+                None => CommandResult::Error(format!("there's no command called `{}`", name)),
+            }
+        },
+        Command::Apropos => {
+            let pattern = try_arg!(pop_string(&mut state.args));
+            let lines = help::apropos(&pattern, commands);
+            state.show_help(lines);
+            CommandResult::Success
+        },
+        Command::StartMacro => {
+            commands::start_macro(state)
+        },
+        Command::EndMacro => {
+            commands::end_macro(state)
+        },
+        Command::CallLastMacro => {
+            if state.recording.is_some() {
+                return CommandResult::Error(String::from("can't call a macro while recording it"));
+            }
+            let macro_steps = state.last_macro.clone();
+            run_macro(state, windows, commands, &macro_steps, steps)
+        },
+        Command::RunMacro(index) => {
+            let macro_steps = state.macros[index].steps.clone();
+            run_macro(state, windows, commands, &macro_steps, steps)
+        },
+        Command::NameLastMacro => {
+            commands::name_last_macro(state)
+        },
+        Command::BindMacro => {
+            commands::bind_macro(state, commands)
+        },
+        Command::SaveMacros => {
+            commands::save_macros(state)
+        },
+        Command::LoadMacros => {
+            commands::load_macros(state, commands)
+        },
+        Command::UniversalArgument => {
+            state.prefix_arg = Some(match state.prefix_arg {
+                Some(PrefixArg::Universal(times)) => PrefixArg::Universal(times + 1),
+                _ => PrefixArg::Universal(1),
+            });
+            CommandResult::Success
+        },
         Command::ZoomIn => {
-            windows[state.window_index].increment_scale();
+            for _ in 0..steps {
+                windows[state.window_index].increment_scale();
+            }
             CommandResult::Success
         },
         Command::ZoomOut => {
-            windows[state.window_index].decrement_scale();
+            for _ in 0..steps {
+                windows[state.window_index].decrement_scale();
+            }
             CommandResult::Success
         },
         Command::Undo => {
-            state.undo();
+            for _ in 0..steps {
+                state.undo();
+            }
             CommandResult::Success
         }
     }
@@ -194,30 +1136,628 @@ fn select_command(state: &mut State, windows: &mut [Box<Window>], command: Comma
 
 pub mod commands {
     use super::CommandResult;
-    use super::Arg;
-    use ::state::State;
+    use super::{pop_string, pop_color, pop_integer};
+    use super::{Command, Input};
+    use ::keymap;
+    use ::macros::{self, Macro};
+    use ::shapes;
+    use ::util;
+    use ::image_buffer::{ImageBuffer, DirtyRect, MAX_IMAGE_SIDE_PX};
+    use std::path::Path;
+    use ::state::{State, Layer, Undo, DrawUndo, PaintMode};
+    use ::ora;
+    use ::palette;
+    use ::palette::Palette;
+    use ::palette_generation::{self, MAX_RAMP_STEPS};
+    use ::quantize;
+    use sdl2::pixels::Color;
     pub fn set_color(state: &mut State) -> CommandResult {
-        let color = state.args.pop().unwrap().coerce_color();
+        let color = try_arg!(pop_color(&mut state.args));
         state.current_color = color;
-        println!("set color");
         CommandResult::Success
     }
 
+    pub fn toggle_paint_mode(state: &mut State) -> CommandResult {
+        state.paint_mode = match state.paint_mode {
+            PaintMode::Blend => PaintMode::Replace,
+            PaintMode::Replace => PaintMode::Blend,
+        };
+        let mode = if state.paint_mode == PaintMode::Replace { "on" } else { "off" };
+        state.info(format!("replace mode {}", mode));
+        CommandResult::Success
+    }
+
+    /*
+     * Arguments `width height` of a tile, also turns the tile grid on
+     */
+    pub fn set_tile_grid(state: &mut State) -> CommandResult {
+        let height = try_arg!(pop_integer(&mut state.args));
+        let width = try_arg!(pop_integer(&mut state.args));
+        if width < 1 || height < 1 {
+            return CommandResult::Error(String::from("tiles have to be at least 1x1"));
+        }
+        state.grid.tile_width = width as usize;
+        state.grid.tile_height = height as usize;
+        state.grid.show_tile_grid = true;
+        CommandResult::Success
+    }
+
+    pub fn set_tile_grid_offset(state: &mut State) -> CommandResult {
+        let y = try_arg!(pop_integer(&mut state.args));
+        let x = try_arg!(pop_integer(&mut state.args));
+        if x < 0 || y < 0 {
+            return CommandResult::Error(String::from("the tile grid offset can't be negative"));
+        }
+        state.grid.tile_offset_x = x as usize;
+        state.grid.tile_offset_y = y as usize;
+        CommandResult::Success
+    }
+
+    /*
+     * For commands given the image of a window, which may have gone since
+     */
+    fn image_exists(state: &State, image_id: usize) -> Result<(), String> {
+        if image_id < state.images.len() {
+            Ok(())
+        } else {
+            Err(format!("no image {}", image_id))
+        }
+    }
+
     pub fn export_png(state: &mut State) -> CommandResult {
-       let out = state.args.pop()
-            .unwrap_or(Arg::String(String::from("tmp/test_out.png")))
-            .coerce_string();
-        state.images[0].save_png_image(out).unwrap();
-        println!("exported png");
-        CommandResult::Success 
+        let out = if state.args.is_empty() {
+            String::from("tmp/test_out.png")
+        } else {
+            try_arg!(pop_string(&mut state.args))
+        };
+        let result = match state.images.first() {
+            Some(image) => image.save_png_image(&out),
+            None => return CommandResult::Error(String::from("no image to export")),
+        };
+        match result {
+            Ok(()) => {
+                state.info(format!("exported {}", out));
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not export {}: {}", out, error)),
+        }
+    }
+
+    pub fn load_png(state: &mut State, image_id: usize) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        try_arg!(image_exists(state, image_id));
+        match ImageBuffer::load_png_image(&path) {
+            Ok(image) => {
+                state.images[image_id] = image;
+                state.undo_stack = vec![];
+                state.info(format!("loaded {}", path));
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not load {}: {}", path, error)),
+        }
+    }
+
+    pub fn new_image(state: &mut State, image_id: usize) -> CommandResult {
+        let height = try_arg!(pop_integer(&mut state.args));
+        let width = try_arg!(pop_integer(&mut state.args));
+        let max_side = MAX_IMAGE_SIDE_PX as isize;
+        if width <= 0 || height <= 0 || width > max_side || height > max_side {
+            return CommandResult::Error(format!(
+                "can't make a {}x{} image, sides go from 1 to {}", width, height, MAX_IMAGE_SIDE_PX));
+        }
+        try_arg!(image_exists(state, image_id));
+        state.images[image_id] = ImageBuffer::new(width as usize, height as usize);
+        state.undo_stack = vec![];
+        CommandResult::Success
+    }
+
+    pub fn export_ora(state: &mut State) -> CommandResult {
+        let out = try_arg!(pop_string(&mut state.args));
+        let result = if state.layers.is_empty() {
+            let layers = [Layer::new(String::from("Layer 0"), 0)];
+            ora::save_ora(out, &layers, &state.images)
+        } else {
+            ora::save_ora(out, &state.layers, &state.images)
+        };
+        match result {
+            Ok(()) => {
+                state.info("exported ora");
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not export ora: {}", error)),
+        }
+    }
+
+    /*
+     * The windows show images by index, so the file needs at least as many
+     * layers as `images_needed`
+     */
+    pub fn import_ora(state: &mut State, images_needed: usize) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        match ora::load_ora(path) {
+            Ok((_, ref images)) if images.len() < images_needed => CommandResult::Error(format!(
+                "could not import ora: it has {} layers and the windows show {} images",
+                images.len(), images_needed)),
+            Ok((layers, images)) => {
+                state.layers = layers;
+                state.images = images;
+                state.undo_stack = vec![];
+                state.info("imported ora");
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not import ora: {}", error)),
+        }
+    }
+
+    pub fn load_palette(state: &mut State) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        let name = ::std::path::Path::new(&path).file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("palette")
+            .to_string();
+        match palette::load_palette(&path) {
+            Ok(colors) => {
+                state.push_palette(Palette::new(name, colors));
+                state.info("loaded palette");
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not load palette: {}", error)),
+        }
+    }
+
+    pub fn save_palette(state: &mut State) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        match palette::save_palette(path, state.current_palette()) {
+            Ok(()) => {
+                state.info("saved palette");
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("could not save palette: {}", error)),
+        }
+    }
+
+    pub fn new_palette(state: &mut State) -> CommandResult {
+        let name = try_arg!(pop_string(&mut state.args));
+        state.push_palette(Palette::new(name, vec![]));
+        CommandResult::Success
+    }
+
+    pub fn duplicate_palette(state: &mut State) -> CommandResult {
+        let copy = {
+            let palette = state.current_palette_mut();
+            Palette::new(palette.name.clone() + " copy", palette.colors.clone())
+        };
+        state.push_palette(copy);
+        CommandResult::Success
+    }
+
+    pub fn rename_palette(state: &mut State) -> CommandResult {
+        let name = try_arg!(pop_string(&mut state.args));
+        state.current_palette_mut().name = name;
+        CommandResult::Success
+    }
+
+    pub fn delete_palette(state: &mut State) -> CommandResult {
+        if state.palettes.len() <= 1 {
+            return CommandResult::Error(String::from("cannot delete the last palette"));
+        }
+        state.palettes.remove(state.current_palette_index);
+        if state.current_palette_index >= state.palettes.len() {
+            state.current_palette_index = state.palettes.len() - 1;
+        }
+        CommandResult::Success
+    }
+
+    pub fn cycle_palette(state: &mut State, step: isize) -> CommandResult {
+        let count = state.palettes.len() as isize;
+        state.current_palette_index =
+            ((state.current_palette_index as isize + step) % count + count) as usize % count as usize;
+        let name = state.palettes[state.current_palette_index].name.clone();
+        state.info(format!("palette {}", name));
+        CommandResult::Success
+    }
+
+    pub fn add_color(state: &mut State) -> CommandResult {
+        let color = state.current_color;
+        state.current_palette_mut().colors.push(color);
+        CommandResult::Success
+    }
+
+    pub fn remove_color(state: &mut State) -> CommandResult {
+        let index = try_arg!(pop_integer(&mut state.args));
+        let colors = &mut state.current_palette_mut().colors;
+        if index >= 0 && (index as usize) < colors.len() {
+            colors.remove(index as usize);
+            CommandResult::Success
+        } else {
+            CommandResult::Error(format!("no color at index {}", index))
+        }
+    }
+
+    pub fn move_color(state: &mut State) -> CommandResult {
+        let to = try_arg!(pop_integer(&mut state.args));
+        let from = try_arg!(pop_integer(&mut state.args));
+        let colors = &mut state.current_palette_mut().colors;
+        let len = colors.len() as isize;
+        if 0 <= from && from < len && 0 <= to && to < len {
+            let color = colors.remove(from as usize);
+            colors.insert(to as usize, color);
+            CommandResult::Success
+        } else {
+            CommandResult::Error(format!("no color at index {} or {}", from, to))
+        }
+    }
+
+    pub fn replace_color(state: &mut State) -> CommandResult {
+        let index = try_arg!(pop_integer(&mut state.args));
+        let color = state.current_color;
+        match state.current_palette_mut().colors.get_mut(index as usize) {
+            Some(slot) if index >= 0 => {
+                *slot = color;
+                CommandResult::Success
+            },
+            _ => CommandResult::Error(format!("no color at index {}", index)),
+        }
+    }
+
+    /*
+     * Appends a palette interpolated between two colors, with
+     * arguments `from to steps`
+     */
+    pub fn generate_ramp(state: &mut State,
+                         generator: fn(Color, Color, usize) -> Vec<Color>,
+                         name: &str) -> CommandResult {
+        let steps = try_arg!(pop_integer(&mut state.args));
+        let to = try_arg!(pop_color(&mut state.args));
+        let from = try_arg!(pop_color(&mut state.args));
+        if steps < 2 || steps > MAX_RAMP_STEPS as isize {
+            return CommandResult::Error(format!(
+                "a ramp takes from 2 to {} steps", MAX_RAMP_STEPS));
+        }
+        state.push_palette(Palette::new(String::from(name), generator(from, to, steps as usize)));
+        CommandResult::Success
+    }
+
+    /*
+     * Arguments `base steps hue-shift saturation-curve`, with the hue
+     * shift in degrees and the saturation curve in percent.
+     */
+    pub fn generate_shading(state: &mut State) -> CommandResult {
+        let saturation_curve = try_arg!(pop_integer(&mut state.args));
+        let hue_shift = try_arg!(pop_integer(&mut state.args));
+        let steps = try_arg!(pop_integer(&mut state.args));
+        let base = try_arg!(pop_color(&mut state.args));
+        if steps < 1 || steps > MAX_RAMP_STEPS as isize {
+            return CommandResult::Error(format!(
+                "shading takes from 1 to {} steps", MAX_RAMP_STEPS));
+        }
+        let colors = palette_generation::shading_ramp(
+            base, steps as usize, hue_shift as f32, saturation_curve as f32 / 100.0);
+        state.push_palette(Palette::new(String::from("shading"), colors));
+        CommandResult::Success
+    }
+
+    pub fn generate_complementary(state: &mut State) -> CommandResult {
+        let base = try_arg!(pop_color(&mut state.args));
+        state.push_palette(Palette::new(String::from("complementary"),
+                                        palette_generation::complementary(base)));
+        CommandResult::Success
+    }
+
+    pub fn generate_triadic(state: &mut State) -> CommandResult {
+        let base = try_arg!(pop_color(&mut state.args));
+        state.push_palette(Palette::new(String::from("triadic"),
+                                        palette_generation::triadic(base)));
+        CommandResult::Success
+    }
+
+    /*
+     * Arguments `order max-colors`, where order is one of `usage`, `hue`
+     * or `luminance`
+     */
+    pub fn extract_palette(state: &mut State, image_id: usize) -> CommandResult {
+        let max_colors = try_arg!(pop_integer(&mut state.args));
+        let order = try_arg!(pop_string(&mut state.args));
+        let order = match quantize::ColorOrder::from_name(&order) {
+            Some(order) => order,
+            None => return CommandResult::Error(format!(
+                "unknown order `{}`, expected usage, hue or luminance", order)),
+        };
+        if max_colors < 1 {
+            return CommandResult::Error(String::from("max colors has to be at least 1"));
+        }
+        try_arg!(image_exists(state, image_id));
+        let colors = quantize::extract_palette(&state.images[image_id], max_colors as usize, order);
+        state.push_palette(Palette::new(String::from("extracted"), colors));
+        CommandResult::Success
+    }
+
+    /*
+     * Argument is the dither: `none`, `floyd-steinberg`, `atkinson` or
+     * `bayer`. The whole remap is undone at once.
+     */
+    pub fn remap_to_palette(state: &mut State, image_id: usize) -> CommandResult {
+        let dither = try_arg!(pop_string(&mut state.args));
+        let dither = match quantize::Dither::from_name(&dither) {
+            Some(dither) => dither,
+            None => return CommandResult::Error(format!(
+                "unknown dither `{}`, expected none, floyd-steinberg, atkinson or bayer", dither)),
+        };
+        try_arg!(image_exists(state, image_id));
+        let remapped = quantize::remap(&state.images[image_id], state.current_palette(), dither);
+
+        let mut undo = Undo::new();
+        {
+            let image = &mut state.images[image_id];
+            for (index, color) in remapped.iter().enumerate() {
+                let old = image.buffer[index];
+                // `RGB` and `RGBA` with full alpha are the same color
+                if util::color_to_rgba(old) != util::color_to_rgba(*color) {
+                    undo.draw_undo.push(DrawUndo::new(image_id, index % image.width,
+                                                      index / image.width, old));
+                }
+            }
+            if !undo.is_empty() {
+                image.set_pixels(remapped);
+            }
+        }
+        if !undo.is_empty() {
+            state.undo_stack.push(undo);
+        }
+        CommandResult::Success
     }
 
     pub fn print(state: &mut State) -> CommandResult {
-        println!("{}", state.args.pop().unwrap().coerce_string());
+        let text = try_arg!(pop_string(&mut state.args));
+        state.info(text);
+        CommandResult::Success
+    }
+
+    /*
+     * Where the canvas cursor is on the image, starting from the pixel
+     * under the mouse the first time it's used
+     */
+    fn cursor(state: &State, image_id: usize) -> (usize, usize) {
+        let image = &state.images[image_id];
+        let (x, y) = match (state.canvas_cursor, state.hover) {
+            (Some(cursor), _) => cursor,
+            (None, Some((hover_image, x, y))) if hover_image == image_id => (x, y),
+            (None, _) => (0, 0),
+        };
+        (x.min(image.width.saturating_sub(1)), y.min(image.height.saturating_sub(1)))
+    }
+
+    /*
+     * The point given as two arguments, or else the cursor
+     */
+    fn point_or_cursor(state: &mut State, image_id: usize) -> Result<(usize, usize), String> {
+        if state.args.is_empty() {
+            return Ok(cursor(state, image_id));
+        }
+        let y = try!(pop_integer(&mut state.args));
+        let x = try!(pop_integer(&mut state.args));
+        let image = &state.images[image_id];
+        if x < 0 || y < 0 || x as usize >= image.width || y as usize >= image.height {
+            return Err(format!("{},{} is outside the {}x{} image", x, y, image.width, image.height));
+        }
+        Ok((x as usize, y as usize))
+    }
+
+    /*
+     * Paints `points` with the current color as one undo step. Each point
+     * should only be given once, or blending goes over it twice. The
+     * pixels are written directly and marked dirty as one rectangle,
+     * since a fill can cover the whole image.
+     */
+    fn paint_points(state: &mut State, image_id: usize, points: &[(usize, usize)]) {
+        let mut undo = Undo::new();
+        let mut dirty: Option<DirtyRect> = None;
+        let color = state.current_color;
+        let paint_mode = state.paint_mode;
+        {
+            let image = &mut state.images[image_id];
+            for &(x, y) in points {
+                if x >= image.width || y >= image.height {
+                    continue;
+                }
+                let old = image.get_point(x, y);
+                let new = match paint_mode {
+                    PaintMode::Replace => color,
+                    PaintMode::Blend => util::blend_over(old, color, 1.0),
+                };
+                if util::color_to_rgba(old) == util::color_to_rgba(new) {
+                    continue;
+                }
+                undo.draw_undo.push(DrawUndo::new(image_id, x, y, old));
+                image.buffer[y * image.width + x] = new;
+                let point = DirtyRect::new(x, y, 1, 1);
+                dirty = Some(dirty.map_or(point, |dirty| dirty.union(&point)));
+            }
+            if let Some(dirty) = dirty {
+                image.mark_dirty(dirty);
+            }
+        }
+        if !undo.is_empty() {
+            state.undo_stack.push(undo);
+        }
+    }
+
+    pub fn move_cursor(state: &mut State, image_id: usize, dx: isize, dy: isize) -> CommandResult {
+        let (x, y) = cursor(state, image_id);
+        let (width, height) = (state.images[image_id].width as isize,
+                               state.images[image_id].height as isize);
+        let x = (x as isize + dx).max(0).min(width - 1);
+        let y = (y as isize + dy).max(0).min(height - 1);
+        state.canvas_cursor = Some((x as usize, y as usize));
+        CommandResult::Success
+    }
+
+    pub fn set_cursor(state: &mut State, image_id: usize) -> CommandResult {
+        let point = try_arg!(point_or_cursor(state, image_id));
+        state.canvas_cursor = Some(point);
+        CommandResult::Success
+    }
+
+    pub fn set_mark(state: &mut State, image_id: usize) -> CommandResult {
+        let point = cursor(state, image_id);
+        state.canvas_cursor = Some(point);
+        state.mark = Some(point);
+        state.info("Mark set");
+        CommandResult::Success
+    }
+
+    pub fn plot(state: &mut State, image_id: usize) -> CommandResult {
+        let point = try_arg!(point_or_cursor(state, image_id));
+        paint_points(state, image_id, &[point]);
+        CommandResult::Success
+    }
+
+    /*
+     * Draws from the mark to the cursor, then moves the mark to the
+     * cursor so lines can be chained
+     */
+    pub fn draw_line(state: &mut State, image_id: usize) -> CommandResult {
+        let to = cursor(state, image_id);
+        let from = match state.mark {
+            Some(mark) => mark,
+            None => return CommandResult::Error(String::from("set the mark first, with C-SPC")),
+        };
+        paint_points(state, image_id, &shapes::line(from, to));
+        state.canvas_cursor = Some(to);
+        state.mark = Some(to);
+        CommandResult::Success
+    }
+
+    pub fn draw_rectangle(state: &mut State, image_id: usize) -> CommandResult {
+        let to = cursor(state, image_id);
+        let from = match state.mark {
+            Some(mark) => mark,
+            None => return CommandResult::Error(String::from("set the mark first, with C-SPC")),
+        };
+        paint_points(state, image_id, &shapes::rectangle(from, to));
+        state.canvas_cursor = Some(to);
+        state.mark = None;
+        CommandResult::Success
+    }
+
+    pub fn fill(state: &mut State, image_id: usize) -> CommandResult {
+        let point = try_arg!(point_or_cursor(state, image_id));
+        let points = shapes::flood_fill(&state.images[image_id], point);
+        paint_points(state, image_id, &points);
+        CommandResult::Success
+    }
+
+    pub fn pick_color(state: &mut State, image_id: usize) -> CommandResult {
+        let (x, y) = try_arg!(point_or_cursor(state, image_id));
+        state.current_color = state.images[image_id].get_point(x, y);
+        CommandResult::Success
+    }
+
+    pub fn start_macro(state: &mut State) -> CommandResult {
+        if state.recording.is_some() {
+            return CommandResult::Error(String::from("already recording a macro"));
+        }
+        state.recording = Some(vec![]);
+        state.info("Recording a macro, C-x ) to stop");
         CommandResult::Success
     }
+
+    pub fn end_macro(state: &mut State) -> CommandResult {
+        match state.recording.take() {
+            Some(steps) => {
+                let count = steps.len();
+                state.last_macro = steps;
+                state.info(format!("Recorded a macro of {} commands", count));
+                CommandResult::Success
+            },
+            None => CommandResult::Error(String::from("not recording a macro")),
+        }
+    }
+
+    /*
+     * Keeps the last macro under a name, replacing any macro called that
+     */
+    pub fn name_last_macro(state: &mut State) -> CommandResult {
+        let name = try_arg!(pop_string(&mut state.args));
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return CommandResult::Error(format!("`{}` can't be a macro name", name));
+        }
+        if Command::from_name(&name).is_some() {
+            return CommandResult::Error(format!("{} is already a command", name));
+        }
+        if state.last_macro.is_empty() {
+            return CommandResult::Error(String::from("no macro has been recorded"));
+        }
+        let steps = state.last_macro.clone();
+        match state.macros.iter().position(|macro_| macro_.name == name) {
+            Some(index) => state.macros[index].steps = steps,
+            None => state.macros.push(Macro { name: name, keys: None, steps: steps }),
+        }
+        CommandResult::BindingsChanged
+    }
+
+    pub fn bind_macro(state: &mut State, commands: &[(Vec<Input>, Command)]) -> CommandResult {
+        let keys = try_arg!(pop_string(&mut state.args));
+        let name = try_arg!(pop_string(&mut state.args));
+        let index = match state.macros.iter().position(|macro_| macro_.name == name) {
+            Some(index) => index,
+            None => return CommandResult::Error(format!("there's no macro called `{}`", name)),
+        };
+        let keys = try_arg!(keymap::parse_key_sequence(&keys));
+        let mut rebound: Vec<(Vec<Input>, Command)> = commands.iter()
+            .filter(|&&(ref inputs, command)| {
+                command != Command::RunMacro(index) || inputs[0] == super::META_X
+            })
+            .cloned()
+            .collect();
+        rebound.push((keys.clone(), Command::RunMacro(index)));
+        try_arg!(keymap::check_conflicts(&rebound));
+        state.macros[index].keys = Some(keys);
+        CommandResult::BindingsChanged
+    }
+
+    pub fn save_macros(state: &mut State) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        match macros::save_macros(Path::new(&path), &state.macros) {
+            Ok(()) => {
+                let count = state.macros.len();
+                state.info(format!("Saved {} macros to {}", count, path));
+                CommandResult::Success
+            },
+            Err(error) => CommandResult::Error(format!("{}: {}", path, error)),
+        }
+    }
+
+    /*
+     * Adds the macros in a file, replacing those with the same names. Their
+     * keys are checked like `bind_macro` checks them, and nothing is added
+     * if any conflict.
+     */
+    pub fn load_macros(state: &mut State, commands: &[(Vec<Input>, Command)]) -> CommandResult {
+        let path = try_arg!(pop_string(&mut state.args));
+        let loaded = try_arg!(macros::load_macros(Path::new(&path), state.current_palette()));
+        if let Some(macro_) = loaded.iter().find(|macro_| Command::from_name(&macro_.name).is_some()) {
+            return CommandResult::Error(format!("{} is already a command", macro_.name));
+        }
+        let mut merged = state.macros.clone();
+        for macro_ in loaded {
+            match merged.iter().position(|old| old.name == macro_.name) {
+                Some(index) => merged[index] = macro_,
+                None => merged.push(macro_),
+            }
+        }
+        let mut rebound = commands.to_vec();
+        macros::rebind(&mut rebound, &merged);
+        try_arg!(keymap::check_conflicts(&rebound).map_err(|message| format!("{}: {}", path, message)));
+        state.macros = merged;
+        CommandResult::BindingsChanged
+    }
 }
 
+/*
+ * How a key is shown in the input stack. Text typed in the minibuffer
+ * comes from `Event::TextInput` instead.
+ */
 pub fn keycode_to_char(keycode: Keycode) -> Option<char> {
     match keycode {
         Keycode::A => Some('a'),
@@ -267,21 +1807,27 @@ pub fn keycode_to_char(keycode: Keycode) -> Option<char> {
 }
 
 /*
- * Parses the input, returning.
+ * Parses the input as what the command being entered expects next.
  * If the input is an argument, also return it.
  */
-pub fn parse_input(input: &str) -> (Input, Option<Arg>) {
-    if let Ok(integer) = input.parse::<isize>() {
-        (Input::Integer, Some(Arg::Integer(integer)))
+pub fn parse_input(input: &str, expected: Option<ArgType>, palette: &[Color])
+                   -> Result<(Input, Option<Arg>), String> {
+    match expected {
+        Some(arg_type) => parse_arg(input, arg_type, palette)
+            .map(|arg| (arg_type.input(), Some(arg)))
+            .ok_or(format!("`{}` isn't {}", input, arg_type.describe())),
+        None => Ok((Input::Exact(input.to_string()), None)),
     }
-    else if let Some(string) = parse_string(input) {
-        (Input::String, Some(Arg::String(string)))
-    }
-    else if let Some(color) = util::parse_color(input) {
-        (Input::Color, Some(Arg::Color(color)))
-    }
-    else {
-        (Input::Exact(input.to_string()), None)
+}
+
+pub fn parse_arg(input: &str, arg_type: ArgType, palette: &[Color]) -> Option<Arg> {
+    match arg_type {
+        ArgType::Integer => input.trim().parse::<isize>().ok().map(Arg::Integer),
+        ArgType::Color => util::parse_color(input, palette).map(Arg::Color),
+        // quotes are optional, since it's known a string is expected
+        ArgType::String => parse_string(input)
+            .or(if input.is_empty() { None } else { Some(input.to_string()) })
+            .map(Arg::String),
     }
 }
 
@@ -295,3 +1841,60 @@ pub fn parse_string(input: &str) -> Option<String> {
     }
     else { None }
 }
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use image_buffer::ImageBuffer;
+    use palette::Palette;
+    use state::State;
+    use util::color_to_rgba;
+    use super::{Arg, CommandResult};
+    use super::commands::remap_to_palette;
+
+    fn remap(state: &mut State, dither: &str) -> Result<(), String> {
+        state.args = vec![Arg::String(String::from(dither))];
+        match remap_to_palette(state, 0) {
+            CommandResult::Success => Ok(()),
+            CommandResult::Error(message) => Err(message),
+            _ => Err(String::from("unexpected result")),
+        }
+    }
+
+    #[test]
+    fn remap_is_undone_at_once() {
+        let before = vec![
+            Color::RGB(20,20,20), Color::RGB(240,240,240),
+            Color::RGBA(0,0,0,0), Color::RGB(0,0,0),
+        ];
+        let mut state = State{images: vec![ImageBuffer::from_buffer(2, 2, before.clone())],
+                              ..State::new()};
+        state.push_palette(Palette::new(String::from("two"),
+                                        vec![Color::RGB(0,0,0), Color::RGB(255,255,255)]));
+
+        assert_eq!(remap(&mut state, "none"), Ok(()));
+        assert_eq!(state.undo_stack.len(), 1);
+        let after: Vec<_> = state.images[0].buffer.iter().map(|&color| color_to_rgba(color)).collect();
+        assert_eq!(after, vec![(0,0,0,255), (255,255,255,255), (0,0,0,0), (0,0,0,255)]);
+
+        // nothing changes the second time, so there's nothing to undo
+        assert_eq!(remap(&mut state, "none"), Ok(()));
+        assert_eq!(state.undo_stack.len(), 1);
+
+        state.undo();
+        assert!(state.undo_stack.is_empty());
+        let undone: Vec<_> = state.images[0].buffer.iter().map(|&color| color_to_rgba(color)).collect();
+        let before: Vec<_> = before.iter().map(|&color| color_to_rgba(color)).collect();
+        assert_eq!(undone, before);
+    }
+
+    #[test]
+    fn remap_takes_known_dithers() {
+        let mut state = State{images: vec![ImageBuffer::new(4, 4)], ..State::new()};
+        for dither in &["none", "floyd-steinberg", "atkinson", "bayer"] {
+            assert_eq!(remap(&mut state, dither), Ok(()));
+        }
+        assert!(remap(&mut state, "ordered").unwrap_err().contains("unknown dither"));
+        assert!(state.undo_stack.is_empty());
+    }
+}
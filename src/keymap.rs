@@ -0,0 +1,367 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use sdl2::keyboard::{Keycode, LALTMOD, LCTRLMOD, LSHIFTMOD, NOMOD};
+
+use input::{self, Input, Command, ArgType};
+
+/*
+ * Key bindings from `~/.config/rusted_pixels/keys.conf`, on top of the
+ * defaults. Each line binds a key sequence to a command by its name,
+ * both in double quotes:
+ *
+ *     # comments take a line of their own or end one
+ *     "C-x C-s" = "export-png"
+ *     "C-c e" = "export-png <string>"  # asks for the path
+ *     "g" = "none"
+ *
+ * That's the whole format: there are no sections, single quotes or
+ * escapes, so neither string can hold a `"`.
+ *
+ * A bare name asks for the command's arguments after the keys, unless
+ * its default keys go without them, as `C-s` runs export-png. Listing
+ * `<integer>`, `<color>` or `<string>` after the name asks for those.
+ * `none` removes a default binding, freeing its keys.
+ */
+pub fn path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".config").join("rusted_pixels").join("keys.conf"))
+}
+
+/*
+ * The default bindings with the config file applied, if there is one
+ */
+pub fn load_bindings() -> Result<Vec<(Vec<Input>, Command)>, String> {
+    let mut commands = input::get_commands();
+    let path = match path() {
+        Some(path) => path,
+        None => return Ok(commands),
+    };
+    let mut text = String::new();
+    match File::open(&path).and_then(|mut file| file.read_to_string(&mut text)) {
+        Ok(_) => {},
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(commands),
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    }
+    try!(apply_config(&mut commands, &text)
+         .map_err(|message| format!("{}:{}", path.display(), message)));
+    try!(check_conflicts(&commands));
+    try!(input::check_bindings(&commands));
+    Ok(commands)
+}
+
+/*
+ * Errors are prefixed with the line number they're on
+ */
+fn apply_config(commands: &mut Vec<(Vec<Input>, Command)>, text: &str) -> Result<(), String> {
+    let defaults = input::get_commands();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keys, name) = try!(parse_line(line)
+                                .map_err(|message| format!("{}: {}", number + 1, message)));
+        let keys = try!(parse_key_sequence(&keys)
+                        .map_err(|message| format!("{}: {}", number + 1, message)));
+
+        // rebinding keys replaces what they did before
+        commands.retain(|&(ref inputs, _)| key_prefix(inputs) != &keys[..]);
+        if name == "none" {
+            continue;
+        }
+        let (command, args) = try!(parse_command(&name, &defaults)
+                                   .map_err(|message| format!("{}: {}", number + 1, message)));
+        let mut inputs = keys;
+        inputs.extend(args.iter().map(ArgType::input));
+        commands.push((inputs, command));
+    }
+    Ok(())
+}
+
+/*
+ * A command name and the arguments its binding asks for, which have to
+ * start its signature
+ */
+fn parse_command(text: &str, defaults: &[(Vec<Input>, Command)])
+                 -> Result<(Command, Vec<ArgType>), String> {
+    let mut words = text.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let command = match Command::from_name(name) {
+        Some(command) => command,
+        None => return Err(format!("there's no command called `{}`", name)),
+    };
+    let args = try!(words.map(|word| match word {
+        "<integer>" => Ok(ArgType::Integer),
+        "<color>" => Ok(ArgType::Color),
+        "<string>" => Ok(ArgType::String),
+        _ => Err(format!("expected <integer>, <color> or <string>, found `{}`", word)),
+    }).collect::<Result<Vec<ArgType>, String>>());
+    let signature = command.signature();
+    if args.len() > signature.len() || &signature[..args.len()] != &args[..] {
+        return Err(format!("{} can't take {}", name, text[name.len()..].trim()));
+    }
+    if !args.is_empty() || runs_without_args(command, defaults) {
+        Ok((command, args))
+    } else {
+        Ok((command, signature.to_vec()))
+    }
+}
+
+/*
+ * Whether some default key runs `command` without asking for arguments
+ */
+fn runs_without_args(command: Command, defaults: &[(Vec<Input>, Command)]) -> bool {
+    defaults.iter().any(|&(ref inputs, bound)| {
+        bound == command && inputs.iter().all(is_key)
+    })
+}
+
+/*
+ * Splits `"keys" = "command"` into its two strings
+ */
+fn parse_line(line: &str) -> Result<(String, String), String> {
+    let (keys, rest) = try!(parse_quoted(line));
+    let rest = rest.trim_left();
+    if !rest.starts_with('=') {
+        return Err(format!("expected `\"keys\" = \"command\"`, found `{}`", line));
+    }
+    let (name, rest) = try!(parse_quoted(rest[1..].trim_left()));
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected `{}` after the command", rest));
+    }
+    Ok((keys, name))
+}
+
+fn parse_quoted(text: &str) -> Result<(String, &str), String> {
+    if !text.starts_with('"') {
+        return Err(format!("expected a quoted string, found `{}`", text));
+    }
+    match text[1..].find('"') {
+        Some(end) => Ok((text[1..(end + 1)].to_string(), &text[(end + 2)..])),
+        None => Err(format!("unterminated string `{}`", text)),
+    }
+}
+
+/*
+ * Keys are separated by spaces, like `C-x C-s`. Each is a key name as SDL
+ * knows them, or one of `RET`, `SPC`, `TAB` and `ESC`, prefixed by any of
+ * `C-`, `M-` and `S-` for control, alt and shift.
+ */
+pub fn parse_key_sequence(text: &str) -> Result<Vec<Input>, String> {
+    let keys = try!(text.split_whitespace().map(parse_key)
+                    .collect::<Result<Vec<Input>, String>>());
+    if keys.is_empty() {
+        return Err(String::from("empty key sequence"));
+    }
+    Ok(keys)
+}
+
+fn parse_key(text: &str) -> Result<Input, String> {
+    let mut modifiers = NOMOD;
+    let mut name = text;
+    loop {
+        let modifier = if name.starts_with("C-") {
+            LCTRLMOD
+        } else if name.starts_with("M-") {
+            LALTMOD
+        } else if name.starts_with("S-") {
+            LSHIFTMOD
+        } else {
+            break;
+        };
+        modifiers = modifiers | modifier;
+        name = &name[2..];
+    }
+    let name = match name {
+        "RET" => "Return",
+        "SPC" => "Space",
+        "TAB" => "Tab",
+        "ESC" => "Escape",
+        name => name,
+    };
+    match Keycode::from_name(name) {
+        Some(keycode) => Ok(Input::Char(keycode, modifiers)),
+        None => Err(format!("unknown key `{}` in `{}`", name, text)),
+    }
+}
+
+fn is_key(input: &Input) -> bool {
+    match *input { Input::Char(..) => true, _ => false }
+}
+
+/*
+ * The keys a binding starts with, before any typed input
+ */
+fn key_prefix(inputs: &[Input]) -> &[Input] {
+    let length = inputs.iter().take_while(|input| is_key(input)).count();
+    &inputs[..length]
+}
+
+/*
+ * Finds bindings that can never run: the same keys bound twice, keys that
+ * start a longer sequence, or a prefix followed by both keys and typed
+ * input.
+ */
+pub fn check_conflicts(commands: &[(Vec<Input>, Command)]) -> Result<(), String> {
+    fn show(inputs: &[Input]) -> String {
+        key_prefix(inputs).iter()
+            .map(show_key)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    for (i, &(ref a, a_command)) in commands.iter().enumerate() {
+        for &(ref b, b_command) in &commands[(i + 1)..] {
+            if a == b {
+                return Err(format!("`{}` is bound to both {} and {}",
+                                   show(a), a_command.name(), b_command.name()));
+            }
+            let (shorter, longer, command) = if a.len() < b.len() {
+                (a, b, a_command)
+            } else {
+                (b, a, b_command)
+            };
+            if &longer[..shorter.len()] == &shorter[..] && shorter.iter().all(is_key) {
+                return Err(format!("`{}` runs {}, so `{}` can't be reached",
+                                   show(shorter), command.name(), show(longer)));
+            }
+            let common = a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count();
+            if common < a.len() && common < b.len() && is_key(&a[common]) != is_key(&b[common])
+                && a[..common].iter().all(is_key) {
+                return Err(format!("`{}` is followed by a key for {} but typed input for {}",
+                                   show(&a[..common]),
+                                   if is_key(&a[common]) { a_command.name() } else { b_command.name() },
+                                   if is_key(&a[common]) { b_command.name() } else { a_command.name() }));
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * A key the way the bindings file writes it
+ */
+pub fn show_key(input: &Input) -> String {
+    match *input {
+        Input::Char(keycode, modifiers) => {
+            let mut text = String::new();
+            if modifiers.intersects(LCTRLMOD) { text.push_str("C-"); }
+            if modifiers.intersects(LALTMOD) { text.push_str("M-"); }
+            if modifiers.intersects(LSHIFTMOD) { text.push_str("S-"); }
+            text + &match keycode {
+                Keycode::Return => String::from("RET"),
+                Keycode::Space => String::from("SPC"),
+                Keycode::Tab => String::from("TAB"),
+                Keycode::Escape => String::from("ESC"),
+                keycode => keycode.name().to_lowercase(),
+            }
+        },
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::keyboard::{Keycode, LALTMOD, LCTRLMOD, LSHIFTMOD, NOMOD};
+    use input::{self, Input, Command, META_X};
+    use super::{apply_config, parse_key_sequence, check_conflicts, show_key};
+
+    fn key(keycode: Keycode) -> Input {
+        Input::Char(keycode, NOMOD)
+    }
+
+    fn ctrl(keycode: Keycode) -> Input {
+        Input::Char(keycode, LCTRLMOD)
+    }
+
+    #[test]
+    fn key_sequences() {
+        assert_eq!(parse_key_sequence("C-x C-s"), Ok(vec![ctrl(Keycode::X), ctrl(Keycode::S)]));
+        assert_eq!(parse_key_sequence("  g  "), Ok(vec![key(Keycode::G)]));
+        assert_eq!(parse_key_sequence("C-M-S-a"),
+                   Ok(vec![Input::Char(Keycode::A, LCTRLMOD | LALTMOD | LSHIFTMOD)]));
+        assert_eq!(parse_key_sequence("RET SPC TAB ESC"),
+                   Ok(vec![key(Keycode::Return), key(Keycode::Space),
+                           key(Keycode::Tab), key(Keycode::Escape)]));
+        assert!(parse_key_sequence("").is_err());
+        assert!(parse_key_sequence("C-x nosuchkey").is_err());
+    }
+
+    #[test]
+    fn shown_keys_parse_back() {
+        for text in &["C-x", "M-S-9", "C-SPC", "RET", "left", "="] {
+            let keys = parse_key_sequence(text).unwrap();
+            assert_eq!(show_key(&keys[0]), *text);
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        assert_eq!(check_conflicts(&input::get_commands()), Ok(()));
+        assert!(check_conflicts(&[(vec![ctrl(Keycode::S)], Command::ExportPng),
+                                  (vec![ctrl(Keycode::S)], Command::Quit)]).is_err());
+        // `C-x` alone would always run before `C-x e` could be typed
+        assert!(check_conflicts(&[(vec![ctrl(Keycode::X)], Command::Quit),
+                                  (vec![ctrl(Keycode::X), key(Keycode::E)],
+                                   Command::CallLastMacro)]).is_err());
+        assert!(check_conflicts(&[(vec![ctrl(Keycode::C), key(Keycode::E)], Command::ZoomIn),
+                                  (vec![ctrl(Keycode::C), Input::Integer],
+                                   Command::RemoveColor)]).is_err());
+        assert_eq!(check_conflicts(&[(vec![ctrl(Keycode::C), key(Keycode::E)], Command::ZoomIn),
+                                     (vec![ctrl(Keycode::C), key(Keycode::O)], Command::ZoomOut),
+                                     (vec![META_X, Input::Exact(String::from("zoom-in"))],
+                                      Command::ZoomIn)]),
+                   Ok(()));
+    }
+
+    fn bound(commands: &[(Vec<Input>, Command)], keys: &str) -> Option<(Vec<Input>, Command)> {
+        let keys = parse_key_sequence(keys).unwrap();
+        commands.iter()
+            .find(|&&(ref inputs, _)| inputs.len() >= keys.len() && &inputs[..keys.len()] == &keys[..])
+            .cloned()
+    }
+
+    #[test]
+    fn config() {
+        let mut commands = input::get_commands();
+        apply_config(&mut commands, "# comment\n\
+                                     \"C-x C-s\" = \"export-png\"\n\
+                                     \"C-c e\" = \"export-png <string>\"\n\
+                                     \"C-c c\" = \"set-color\" # asks for a color\n\
+                                     \"g\" = \"none\"\n\
+                                     \"f\" = \"zoom-out\"\n").unwrap();
+        // like the default C-s, it exports without asking for a path
+        assert_eq!(bound(&commands, "C-x C-s"),
+                   Some((vec![ctrl(Keycode::X), ctrl(Keycode::S)], Command::ExportPng)));
+        assert_eq!(bound(&commands, "C-c e"),
+                   Some((vec![ctrl(Keycode::C), key(Keycode::E), Input::String],
+                         Command::ExportPng)));
+        assert_eq!(bound(&commands, "C-c c"),
+                   Some((vec![ctrl(Keycode::C), key(Keycode::C), Input::Color],
+                         Command::SetColor)));
+        assert_eq!(bound(&commands, "g"), None);
+        assert_eq!(bound(&commands, "f"), Some((vec![key(Keycode::F)], Command::ZoomOut)));
+        assert_eq!(commands.iter().filter(|&&(ref inputs, _)| inputs == &vec![key(Keycode::F)])
+                   .count(), 1);
+    }
+
+    #[test]
+    fn config_errors() {
+        let mut commands = input::get_commands();
+        let error = apply_config(&mut commands, "# bindings\n\"C-c x\" = \"no-such-command\"\n");
+        assert_eq!(error, Err(String::from("2: there's no command called `no-such-command`")));
+        assert!(apply_config(&mut commands, "\"C-c x\" = \"set-color <integer>\"").is_err());
+        assert!(apply_config(&mut commands, "\"C-c x\" = \"zoom-in <integer>\"").is_err());
+        assert!(apply_config(&mut commands, "\"C-c x\" = \"zoom-in\" extra").is_err());
+        assert!(apply_config(&mut commands, "\"C-c x\" = zoom-in").is_err());
+        // it isn't TOML, so sections and single quotes aren't understood
+        assert!(apply_config(&mut commands, "[keys]").is_err());
+        assert!(apply_config(&mut commands, "'C-c x' = 'zoom-in'").is_err());
+        assert!(apply_config(&mut commands, "\"C-q x\" = \"zoom-in\"").is_ok());
+        // `C-q` quits, so `C-q x` is unreachable
+        assert!(check_conflicts(&commands).is_err());
+    }
+}
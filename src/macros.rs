@@ -0,0 +1,384 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use sdl2::pixels::Color;
+
+use input::{self, Input, Command, Arg, ArgType};
+use keymap;
+use util;
+
+/*
+ * A command as it was run, with its arguments and count
+ */
+#[derive(Clone)]
+pub struct Invocation {
+    pub command: Command,
+    pub args: Vec<Arg>,
+    pub count: Option<isize>,
+}
+
+impl Invocation {
+    /*
+     * The invocation as a line like `4 zoom-in` or `export-png 'out.png'`,
+     * which `parse_invocation` reads back
+     */
+    pub fn to_line(&self) -> String {
+        let mut parts = vec![];
+        if let Some(count) = self.count {
+            parts.push(count.to_string());
+        }
+        parts.push(String::from(self.command.name()));
+        for arg in &self.args {
+            parts.push(match *arg {
+                Arg::Integer(integer) => integer.to_string(),
+                Arg::String(ref string) => format!("'{}'", escape(string)),
+                Arg::Color(color) => {
+                    let (r,g,b,a) = util::color_to_rgba(color);
+                    format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+                },
+            });
+        }
+        parts.join(" ")
+    }
+}
+
+/*
+ * A recorded macro given a name, which runs it after `M-x`, and maybe
+ * keys of its own
+ */
+#[derive(Clone)]
+pub struct Macro {
+    pub name: String,
+    pub keys: Option<Vec<Input>>,
+    pub steps: Vec<Invocation>,
+}
+
+/*
+ * The bindings that run the named macros
+ */
+pub fn bindings(macros: &[Macro]) -> Vec<(Vec<Input>, Command)> {
+    let mut commands = vec![];
+    for (index, macro_) in macros.iter().enumerate() {
+        commands.push((vec![input::META_X, Input::Exact(macro_.name.clone())],
+                       Command::RunMacro(index)));
+        if let Some(ref keys) = macro_.keys {
+            commands.push((keys.clone(), Command::RunMacro(index)));
+        }
+    }
+    commands
+}
+
+/*
+ * Replaces the macro bindings in `commands` with those of `macros`
+ */
+pub fn rebind(commands: &mut Vec<(Vec<Input>, Command)>, macros: &[Macro]) {
+    commands.retain(|&(_, command)| match command {
+        Command::RunMacro(_) => false,
+        _ => true,
+    });
+    commands.extend(bindings(macros));
+}
+
+/*
+ * Quotes and backslashes in strings are written with a backslash before
+ * them, so `it's.png` is saved as `'it\'s.png'`
+ */
+fn escape(string: &str) -> String {
+    string.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+/*
+ * Splits a line into words, keeping what's in single quotes together.
+ * Other backslashes in quotes are kept as they are, as in Windows paths.
+ */
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' {
+            chars.next();
+            let mut word = String::new();
+            let mut terminated = false;
+            while let Some(c) = chars.next() {
+                if c == '\'' {
+                    terminated = true;
+                    break;
+                }
+                let next = chars.peek().cloned();
+                match next {
+                    Some(escaped) if c == '\\' && (escaped == '\'' || escaped == '\\') => {
+                        word.push(escaped);
+                        chars.next();
+                    },
+                    _ => word.push(c),
+                }
+            }
+            if !terminated {
+                return Err(format!("unterminated string in `{}`", line));
+            }
+            words.push(format!("'{}'", word));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+    Ok(words)
+}
+
+/*
+ * Reads a line like `Invocation::to_line` writes. Trailing arguments can
+ * be left out, as in bindings, and colors can take two words, as in
+ * `rgb 255,0,0`.
+ */
+pub fn parse_invocation(line: &str, palette: &[Color]) -> Result<Invocation, String> {
+    let words = try!(split_words(line));
+    let mut words = words.iter().map(|word| &word[..]).peekable();
+
+    let mut count = None;
+    let mut name = match words.next() {
+        Some(word) => word,
+        None => return Err(String::from("empty line")),
+    };
+    if let Ok(integer) = name.parse::<isize>() {
+        count = Some(integer);
+        name = match words.next() {
+            Some(word) => word,
+            None => return Err(format!("a count but no command in `{}`", line)),
+        };
+    }
+    let command = match Command::from_name(name) {
+        Some(command) => command,
+        None => return Err(format!("there's no command called `{}`", name)),
+    };
+
+    let mut args = vec![];
+    for &arg_type in command.signature() {
+        let word = match words.next() {
+            Some(word) => word,
+            None => break,
+        };
+        let arg = match input::parse_arg(word, arg_type, palette) {
+            Some(arg) => arg,
+            None => {
+                let joined = match words.peek() {
+                    Some(next) if arg_type == ArgType::Color => format!("{} {}", word, next),
+                    _ => String::new(),
+                };
+                match input::parse_arg(&joined, arg_type, palette) {
+                    Some(arg) => { words.next(); arg },
+                    None => return Err(format!("`{}` isn't {}", word, arg_type.describe())),
+                }
+            },
+        };
+        args.push(arg);
+    }
+    if let Some(extra) = words.next() {
+        return Err(format!("{} doesn't take `{}`", command.name(), extra));
+    }
+    Ok(Invocation {
+        command: command,
+        args: args,
+        count: count,
+    })
+}
+
+/*
+ * Macros are saved as blocks of invocations, named and maybe with keys:
+ *
+ *     macro outline C-c o
+ *     set-color #000000ff
+ *     end
+ */
+pub fn save_macros(path: &Path, macros: &[Macro]) -> io::Result<()> {
+    let mut file = try!(File::create(path));
+    for macro_ in macros {
+        let keys = macro_.keys.as_ref()
+            .map(|keys| keys.iter().map(keymap::show_key).collect::<Vec<String>>().join(" "))
+            .unwrap_or(String::new());
+        try!(writeln!(file, "macro {} {}", macro_.name, keys));
+        for step in &macro_.steps {
+            try!(writeln!(file, "{}", step.to_line()));
+        }
+        try!(writeln!(file, "end"));
+    }
+    Ok(())
+}
+
+pub fn load_macros(path: &Path, palette: &[Color]) -> Result<Vec<Macro>, String> {
+    let file = try!(File::open(path).map_err(|error| format!("{}: {}", path.display(), error)));
+    let mut macros = vec![];
+    let mut current: Option<Macro> = None;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|error| format!("{}: {}", path.display(), error)));
+        let line = line.trim();
+        let error = |message: String| format!("{}:{}: {}", path.display(), number + 1, message);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "end" {
+            match current.take() {
+                Some(macro_) => macros.push(macro_),
+                None => return Err(error(String::from("`end` without `macro`"))),
+            }
+        } else if line.starts_with("macro ") {
+            if current.is_some() {
+                return Err(error(String::from("`macro` before the previous one's `end`")));
+            }
+            let mut words = line["macro ".len()..].trim().splitn(2, ' ');
+            let name = words.next().unwrap_or("").to_string();
+            if name.is_empty() {
+                return Err(error(String::from("a macro needs a name")));
+            }
+            let keys = match words.next().map(str::trim) {
+                Some(keys) if !keys.is_empty() =>
+                    Some(try!(keymap::parse_key_sequence(keys).map_err(&error))),
+                _ => None,
+            };
+            current = Some(Macro { name: name, keys: keys, steps: vec![] });
+        } else {
+            match current {
+                Some(ref mut macro_) =>
+                    macro_.steps.push(try!(parse_invocation(line, palette).map_err(&error))),
+                None => return Err(error(format!("`{}` outside of a macro", line))),
+            }
+        }
+    }
+    if current.is_some() {
+        return Err(format!("{}: the last macro has no `end`", path.display()));
+    }
+    Ok(macros)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env,fs};
+    use sdl2::pixels::Color;
+    use input::{Arg, Command};
+    use keymap;
+    use super::{Invocation, Macro, split_words, parse_invocation, save_macros, load_macros};
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn splitting() {
+        assert_eq!(split_words("  4 zoom-in "), Ok(words(&["4", "zoom-in"])));
+        assert_eq!(split_words("export-png 'out dir/a b.png'"),
+                   Ok(words(&["export-png", "'out dir/a b.png'"])));
+        assert_eq!(split_words("export-png 'it\\'s.png'"), Ok(words(&["export-png", "'it's.png'"])));
+        assert_eq!(split_words("export-png 'C:\\out\\a.png'"),
+                   Ok(words(&["export-png", "'C:\\out\\a.png'"])));
+        assert_eq!(split_words("export-png 'a\\\\'"), Ok(words(&["export-png", "'a\\'"])));
+        assert_eq!(split_words("''"), Ok(words(&["''"])));
+        assert_eq!(split_words(""), Ok(vec![]));
+        assert!(split_words("export-png 'out.png").is_err());
+        assert!(split_words("export-png 'out.png\\'").is_err());
+    }
+
+    #[test]
+    fn parsing() {
+        let invocation = parse_invocation("3 zoom-in", &[]).unwrap();
+        assert_eq!(invocation.command, Command::ZoomIn);
+        assert_eq!(invocation.count, Some(3));
+        assert!(invocation.args.is_empty());
+
+        let invocation = parse_invocation("set-color rgb 255,0,0", &[]).unwrap();
+        assert_eq!(invocation.command, Command::SetColor);
+        assert_eq!(invocation.to_line(), "set-color #ff0000ff");
+
+        let palette = [Color::RGB(1,2,3)];
+        let invocation = parse_invocation("generate-ramp p0 #ffffff 4", &palette).unwrap();
+        assert_eq!(invocation.to_line(), "generate-ramp #010203ff #ffffffff 4");
+
+        // trailing arguments can be left out
+        let invocation = parse_invocation("fill", &[]).unwrap();
+        assert!(invocation.args.is_empty());
+
+        assert!(parse_invocation("", &[]).is_err());
+        assert!(parse_invocation("4", &[]).is_err());
+        assert!(parse_invocation("no-such-command", &[]).is_err());
+        assert!(parse_invocation("fill x 3", &[]).is_err());
+        assert!(parse_invocation("zoom-in 3", &[]).is_err());
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        let invocations = vec![
+            Invocation { command: Command::ZoomIn, args: vec![], count: Some(-2) },
+            Invocation { command: Command::Fill, args: vec![Arg::Integer(3), Arg::Integer(4)],
+                         count: None },
+            Invocation { command: Command::SetColor, args: vec![Arg::Color(Color::RGBA(1,2,3,4))],
+                         count: None },
+            Invocation { command: Command::ExportPng,
+                         args: vec![Arg::String(String::from("it's a \\ path.png"))],
+                         count: None },
+        ];
+        for invocation in invocations {
+            let line = invocation.to_line();
+            let parsed = parse_invocation(&line, &[]).unwrap();
+            assert_eq!(parsed.command, invocation.command);
+            assert_eq!(parsed.count, invocation.count);
+            assert_eq!(parsed.to_line(), line);
+        }
+        let line = Invocation { command: Command::ExportPng,
+                                args: vec![Arg::String(String::from("it's.png"))],
+                                count: None }.to_line();
+        assert_eq!(line, "export-png 'it\\'s.png'");
+        match parse_invocation(&line, &[]).unwrap().args[0] {
+            Arg::String(ref string) => assert_eq!(string, "it's.png"),
+            _ => panic!("expected a string"),
+        }
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let path = env::temp_dir().join("rusted_pixels_macros_test");
+        let macros = vec![
+            Macro { name: String::from("outline"),
+                    keys: Some(keymap::parse_key_sequence("C-c o").unwrap()),
+                    steps: vec![parse_invocation("set-color #000000", &[]).unwrap(),
+                                parse_invocation("2 draw-rectangle", &[]).unwrap()] },
+            Macro { name: String::from("save"),
+                    keys: None,
+                    steps: vec![parse_invocation("export-png 'it\\'s.png'", &[]).unwrap()] },
+        ];
+        save_macros(&path, &macros).unwrap();
+        let loaded = load_macros(&path, &[]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), macros.len());
+        for (loaded, saved) in loaded.iter().zip(macros.iter()) {
+            assert_eq!(loaded.name, saved.name);
+            assert_eq!(loaded.keys, saved.keys);
+            let lines = |macro_: &Macro| macro_.steps.iter()
+                .map(|step| step.to_line())
+                .collect::<Vec<String>>();
+            assert_eq!(lines(loaded), lines(saved));
+        }
+    }
+
+    #[test]
+    fn file_errors() {
+        let path = env::temp_dir().join("rusted_pixels_macros_errors_test");
+        for text in &["zoom-in\n", "macro\nend\n", "macro a\nmacro b\nend\nend\n",
+                      "end\n", "macro a\nzoom-in\n", "macro a\nno-such-command\nend\n"] {
+            fs::File::create(&path).and_then(|mut file| {
+                use std::io::Write;
+                file.write_all(text.as_bytes())
+            }).unwrap();
+            assert!(load_macros(&path, &[]).is_err(), "`{}` should fail to load", text);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
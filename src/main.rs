@@ -3,12 +3,14 @@ extern crate sdl2_ttf;
 extern crate png;
 #[macro_use]extern crate bitflags;
 extern crate time;
+extern crate zip;
 
 use std::path::Path;
+use std::io::Write;
 
 use sdl2::pixels::Color;
 use sdl2::event::Event;
-use sdl2::keyboard::{Keycode, Mod};
+use sdl2::keyboard::{Keycode, Mod, LCTRLMOD, RCTRLMOD, LALTMOD, RALTMOD};
 use sdl2::render::BlendMode;
 use sdl2::mouse::Mouse;
 use sdl2::video::Window as SdlWindow;
@@ -20,6 +22,18 @@ pub mod windows;
 pub mod state;
 pub mod input;
 pub mod util;
+pub mod ora;
+pub mod palette;
+pub mod palette_generation;
+pub mod color_space;
+pub mod quantize;
+pub mod completion;
+pub mod history;
+pub mod keymap;
+pub mod macros;
+pub mod shapes;
+pub mod help;
+pub mod script;
 
 use input::*;
 
@@ -28,6 +42,17 @@ use state::{State, Undo};
 use windows::*;
 
 pub fn main() {
+    let arguments: Vec<String> = std::env::args().collect();
+    if arguments.len() > 1 && arguments[1] == "--script" {
+        match arguments.get(2) {
+            Some(script_path) => run_script(Path::new(script_path)),
+            None => {
+                writeln!(std::io::stderr(), "usage: {} --script <file.rpcmd>", arguments[0]).ok();
+                std::process::exit(2);
+            },
+        }
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let ttf_context = sdl2_ttf::init().unwrap();
     let window = init_sdl_window(&sdl_context);
@@ -38,12 +63,25 @@ pub fn main() {
     let font_path = Path::new("fonts/SourceCodePro_Regular.ttf");
     let mut font = ttf_context.load_font(font_path, 128).unwrap();
 
-    let commands = input::get_commands();
+    if let Err(message) = input::check_bindings(&input::get_commands()) {
+        panic!("{}", message);
+    }
     let mut windows: Vec<Box<Window>> = initialize_windows();
     let mut state = State{images: vec![
         ImageBuffer::load_png_image(&path::PathBuf::from("test.png")).unwrap(),
         ImageBuffer::new(32,64)
     ], ..State::new()};
+    match history::History::load() {
+        Ok(history) => state.history = history,
+        Err(error) => state.warning(format!("Couldn't load the input history: {}", error)),
+    }
+    let mut commands = match keymap::load_bindings() {
+        Ok(commands) => commands,
+        Err(message) => {
+            state.error(format!("Using the default keys, {}", message));
+            input::get_commands()
+        },
+    };
     
     let mut event_pump = sdl_context.event_pump().unwrap();
     
@@ -58,14 +96,40 @@ pub fn main() {
                                          x, y, .. } => {
                     handle_mouse_left_down(&mut state, &windows, x, y);
                 },
-                Event::MouseMotion { x, y, .. } => {
+                Event::MouseButtonDown { mouse_btn: Mouse::Middle,
+                                         x, y, .. } => {
+                    state.panning_window = windows.iter()
+                        .position(|window| window.contains_point(x, y));
+                },
+                Event::MouseButtonUp { mouse_btn: Mouse::Middle, .. } => {
+                    state.panning_window = None;
+                },
+                Event::MouseWheel { y: scroll, .. } => {
+                    let (x, y) = (state.mouse_x, state.mouse_y);
+                    for window in windows.iter_mut() {
+                        if window.contains_point(x, y) {
+                            window.zoom_at(x, y, scroll as isize);
+                        }
+                    }
+                },
+                Event::MouseMotion { x, y, xrel, yrel, .. } => {
+                    if let Some(index) = state.panning_window {
+                        windows[index].pan(xrel, yrel);
+                    }
                     handle_mouse_motion(&mut state, &windows, x, y);
                 },
                 Event::MouseButtonUp { mouse_btn: Mouse::Left, x, y, .. } => {
                     handle_mouse_release(&mut state, x, y);
                 },
+                Event::TextInput { text, .. } => {
+                    if !state.suppress_text_input && !state.input.is_empty() {
+                        state.completions.clear();
+                        state.history.reset();
+                        state.insert_text(&text);
+                    }
+                },
                 Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
-                    if handle_key_down(&mut state, windows.as_mut() , &commands,
+                    if handle_key_down(&mut state, windows.as_mut() , &mut commands,
                                        keycode, keymod) {
                         break 'main_loop;
                     }
@@ -74,6 +138,10 @@ pub fn main() {
             }
         }
 
+        update_status(&mut state, &windows);
+        let (_, window_height) = renderer.window().unwrap().size();
+        state.help_page = help_window::page_lines(window_height);
+
         renderer.set_draw_color(Color::RGB(0, 0, 0));
         renderer.clear();
         renderer.set_draw_color(Color::RGB(255,255,255));
@@ -89,6 +157,10 @@ pub fn main() {
             std::thread::sleep(std_duration);
         }
     }
+
+    if let Err(error) = state.history.save() {
+        writeln!(std::io::stderr(), "Couldn't save the input history: {}", error).ok();
+    }
 }
 
 fn handle_mouse_left_down(state: &mut State, windows: &[Box<Window>],
@@ -121,62 +193,250 @@ fn handle_mouse_motion(state: &mut State, windows: &[Box<Window>],
     }
 }
 
-fn handle_key_down(state: &mut State, windows: &mut [Box<Window>], commands: &[(Vec<Input>, Command)],
+/*
+ * Refreshes what the status window shows about the windows, since it
+ * can't see them itself
+ */
+fn update_status(state: &mut State, windows: &[Box<Window>]) {
+    let (x, y) = (state.mouse_x, state.mouse_y);
+    let hover = windows.iter()
+        .filter_map(|window| window.pixel_at(state, x, y))
+        .next();
+    state.hover = hover;
+    state.zoom = windows[state.window_index].scale();
+    state.active_image_id = windows[state.window_index].image_id();
+}
+
+fn handle_key_down(state: &mut State, windows: &mut [Box<Window>],
+                   commands: &mut Vec<(Vec<Input>, Command)>,
                    keycode: Keycode, keymod: Mod) -> bool {
+    let ctrl = keymod.intersects(LCTRLMOD | RCTRLMOD);
+    let alt = keymod.intersects(LALTMOD | RALTMOD);
+    // the text of a key used here shouldn't end up in the minibuffer too
+    state.suppress_text_input = true;
+    if keycode != Keycode::Tab {
+        state.completions.clear();
+    }
+    // going through the history stops at any other key
+    if !(alt && (keycode == Keycode::P || keycode == Keycode::N)) {
+        state.history.reset();
+    }
+    // modifiers only count together with another key
+    match keycode {
+        Keycode::LCtrl | Keycode::RCtrl | Keycode::LAlt | Keycode::RAlt
+            | Keycode::LShift | Keycode::RShift | Keycode::LGui | Keycode::RGui => return false,
+        _ => {}
+    }
+    // SPC and DEL page through the help window, the arrows go by line
+    if state.help_text.is_some() && !ctrl && !alt {
+        let page = state.help_page as isize;
+        let lines = match keycode {
+            Keycode::Space => page,
+            Keycode::Backspace => -page,
+            Keycode::Down => 1,
+            Keycode::Up => -1,
+            _ => 0,
+        };
+        if lines != 0 {
+            state.scroll_help(lines);
+            return false;
+        }
+    }
+    // the help window closes at any other key, and only ESC and q do
+    // nothing else
+    if state.help_text.take().is_some()
+        && (keycode == Keycode::Escape || (keycode == Keycode::Q && !ctrl && !alt)) {
+        return false;
+    }
+    // keys read by `C-h k` are described instead of run
+    if let Some(brief) = state.describing_key {
+        state.input.push(Input::Char(keycode, keymod));
+        let complete = match input::interpret_input(&state.input, commands) {
+            Err(InterpretErr::RequiresMoreInput) => !input::expects_key(&state.input, commands),
+            _ => true,
+        };
+        if complete {
+            let keys = std::mem::replace(&mut state.input, vec![]);
+            state.describing_key = None;
+            help::describe_key(state, &keys, commands, brief);
+        }
+        return false;
+    }
     // every command begins with a single key
     if keycode == Keycode::Backspace {
-        state.input_buffer.pop();
+        state.delete_backward();
         return false;
     }
     if state.input.is_empty() {
-        state.input.push(Input::Char(keycode,keymod));
-        match execute_command(state, windows, commands) {
-            CommandResult::Quit => { return true },
-            _ => {}
+        // unbound digits are a count for the next command
+        if let Some(digit) = input::digit_of(keycode) {
+            if !ctrl && !alt && !input::is_bound(&[Input::Char(keycode, keymod)], commands) {
+                state.push_prefix_digit(digit);
+                return false;
+            }
         }
+        state.input.push(Input::Char(keycode,keymod));
+        return execute_input(state, windows, commands);
     }
     // If escape is pressed, clear input buffer or pop
     // input stack
     else if keycode == Keycode::Escape {
         if !state.input_buffer.is_empty() {
-            state.input_buffer = String::new();
+            state.set_input_buffer(String::new());
         } else {
-            state.input.pop();
+            let popped = state.input.pop();
+            if popped.as_ref().and_then(input::ArgType::from_input).is_some() {
+                state.args.pop();
+            }
         }
     }
-    else if keycode == Keycode::Return {
-        let (input_type, arg)
-            = input::parse_input(&state.input_buffer);
-        state.input.push(input_type);
-        if let Some(arg) = arg {
-            state.args.push(arg);
+    // the rest of a sequence like `C-x C-s`
+    else if input::expects_key(&state.input, commands) {
+        state.input.push(Input::Char(keycode,keymod));
+        return execute_input(state, windows, commands);
+    }
+    // line editing, emacs style
+    else if ctrl && keycode == Keycode::A {
+        state.input_cursor = 0;
+    }
+    else if ctrl && keycode == Keycode::E {
+        state.input_cursor = state.input_buffer.len();
+    }
+    else if ctrl && keycode == Keycode::K {
+        state.kill_to_end();
+    }
+    else if ctrl && keycode == Keycode::W {
+        state.kill_word_backward();
+    }
+    else if keycode == Keycode::Left || (ctrl && keycode == Keycode::B) {
+        state.cursor_left();
+    }
+    else if keycode == Keycode::Right || (ctrl && keycode == Keycode::F) {
+        state.cursor_right();
+    }
+    else if alt && keycode == Keycode::P {
+        let expected = input::expected_arg(&state.input, commands);
+        let entry = state.history.previous(expected, &state.input_buffer);
+        match entry {
+            Some(entry) => state.set_input_buffer(entry),
+            None => state.info("No earlier input"),
         }
-                        state.input_buffer = String::new();
-        match execute_command(state, windows, commands) {
-            CommandResult::Quit => { return true },
-            _ => {}
+    }
+    else if alt && keycode == Keycode::N {
+        let expected = input::expected_arg(&state.input, commands);
+        if let Some(entry) = state.history.next(expected) {
+            state.set_input_buffer(entry);
         }
     }
-    else {
-        if let Some(chr) = input::keycode_to_char(keycode) {
-            state.input_buffer.push(chr);
+    else if keycode == Keycode::Tab {
+        let candidates = completion::complete(&state.input, &state.input_buffer, commands,
+                                              &state.macros);
+        if candidates.is_empty() {
+            state.warning("No match");
+        } else if candidates.len() == 1 {
+            state.set_input_buffer(candidates[0].clone());
+            state.completions.clear();
+        } else {
+            let prefix = completion::common_prefix(&candidates);
+            if prefix.len() > state.input_buffer.len() && prefix.starts_with(&state.input_buffer[..]) {
+                state.set_input_buffer(prefix);
+            }
+            state.completions = candidates;
         }
     }
+    else if keycode == Keycode::Return {
+        let expected = input::expected_arg(&state.input, commands);
+        let parsed = input::parse_input(&state.input_buffer, expected,
+                                        state.current_palette());
+        match parsed {
+            Ok((input_type, arg)) => {
+                state.history.add(expected, &state.input_buffer);
+                state.input.push(input_type);
+                if let Some(arg) = arg {
+                    state.args.push(arg);
+                }
+                state.set_input_buffer(String::new());
+                return execute_input(state, windows, commands);
+            },
+            // keep the buffer so it can be corrected
+            Err(message) => state.error(message),
+        }
+    }
+    // anything else is typed through `Event::TextInput`
+    else {
+        state.suppress_text_input = ctrl || alt;
+    }
     return false;
 }
 
+/*
+ * Executes the input if it's a whole command, returning whether to quit
+ */
+fn execute_input(state: &mut State, windows: &mut [Box<Window>],
+                 commands: &mut Vec<(Vec<Input>, Command)>) -> bool {
+    match execute_command(state, windows, commands) {
+        CommandResult::Quit => true,
+        CommandResult::ReloadConfig => {
+            match keymap::load_bindings() {
+                Ok(mut bindings) => {
+                    bindings.extend(macros::bindings(&state.macros));
+                    // the keys of a macro may clash with the new bindings
+                    match keymap::check_conflicts(&bindings) {
+                        Ok(()) => {
+                            *commands = bindings;
+                            state.info("Reloaded the key bindings");
+                        },
+                        Err(message) => {
+                            state.error(format!("Kept the old key bindings: {}", message));
+                        },
+                    }
+                },
+                // the old bindings are kept
+                Err(message) => state.error(message),
+            }
+            false
+        },
+        CommandResult::BindingsChanged => {
+            macros::rebind(commands, &state.macros);
+            false
+        },
+        _ => false,
+    }
+}
+
+/*
+ * Runs a script without opening a window, on a blank 32x32 image, and
+ * exits with 1 if it fails
+ */
+fn run_script(script_path: &Path) -> ! {
+    let mut windows = initialize_windows();
+    let mut state = State{images: vec![ImageBuffer::new(32,32)], ..State::new()};
+    let mut commands = input::get_commands();
+    match script::run_script(script_path, &mut state, &mut windows, &mut commands) {
+        Ok(()) => std::process::exit(0),
+        Err(message) => {
+            writeln!(std::io::stderr(), "{}", message).ok();
+            std::process::exit(1);
+        },
+    }
+}
+
 fn initialize_windows() -> Vec<Box<Window>> {
     let gray = Color::RGB(50, 50, 50);
     let lighter_gray = Color::RGB(100, 100, 100);
-    vec![Box::new(DrawingWindow::new(50, 50, 8, lighter_gray, 0)),
-         Box::new(PreviewWindow(DrawingWindow::new(400, 50, 1, gray, 0))),
-         Box::new(DrawingWindow::new(400, 400, 2, gray, 0)),
-         Box::new(PaletteWindow{x: 400,y: 100,palette_id: 0}),
+    vec![Box::new(DrawingWindow::new(50, 50, 340, 500, 8, lighter_gray, 0)),
+         Box::new(PreviewWindow(DrawingWindow::new(400, 50, 64, 48, 1, gray, 0))),
+         Box::new(DrawingWindow::new(400, 400, 190, 150, 2, gray, 0)),
+         Box::new(PaletteWindow{x: 400,y: 100,palette_id: None}),
+         Box::new(ColorPickerWindow::new(600, 100)),
+         Box::new(HelpWindow::new()),
          Box::new(StatusWindow::new())]
 }
 
 fn init_sdl_window(sdl_context: &Sdl) -> SdlWindow {
     let video_subsystem = sdl_context.video().unwrap();
+    // the minibuffer is typed into through `Event::TextInput`
+    video_subsystem.text_input().start();
     video_subsystem.window("rusted pixels", 800, 600)
         .resizable()
         .build()
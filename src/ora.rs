@@ -0,0 +1,341 @@
+use png;
+use zip;
+use zip::write::FileOptions;
+use std::{fs,io,path};
+use std::io::{Read,Write};
+
+use image_buffer::{ImageBuffer, MAX_IMAGE_SIDE_PX};
+use state::Layer;
+use util;
+
+/*
+ * OpenRaster (.ora) support. An OpenRaster file is a zip container holding
+ * a `mimetype` entry, a `stack.xml` describing the layers and one PNG per
+ * layer, plus a flattened image and a thumbnail.
+ */
+
+const MIMETYPE: &'static str = "image/openraster";
+const THUMBNAIL_MAX_PX: usize = 256;
+
+/*
+ * Reads every layer of an OpenRaster file. The layers are returned bottom
+ * to top, and layer `i` refers to image `i` in the returned images.
+ */
+pub fn load_ora<P: AsRef<path::Path>>(path: P) -> io::Result<(Vec<Layer>, Vec<ImageBuffer>)> {
+    let mut archive = try!(zip::ZipArchive::new(try!(fs::File::open(path))).map_err(zip_error));
+
+    let mut stack = String::new();
+    {
+        let mut file = try!(archive.by_name("stack.xml").map_err(zip_error));
+        try!(file.read_to_string(&mut stack));
+    }
+
+    let (width, height) = match find_elements(&stack, "image").first() {
+        Some(image) => (try!(canvas_side(image, "w")), try!(canvas_side(image, "h"))),
+        None => return Err(invalid_data("stack.xml has no <image> element")),
+    };
+
+    let mut layers = Vec::new();
+    let mut images = Vec::new();
+    // stack.xml lists the topmost layer first
+    for (i, element) in find_elements(&stack, "layer").iter().rev().enumerate() {
+        let src = match attribute(element, "src") {
+            Some(src) => src,
+            None => return Err(invalid_data("<layer> without a `src` attribute")),
+        };
+        let mut data = Vec::new();
+        {
+            let mut file = try!(archive.by_name(src).map_err(zip_error));
+            try!(file.read_to_end(&mut data));
+        }
+        let image = try!(ImageBuffer::read_png(&data[..]));
+        let x = attribute(element, "x").and_then(|x| x.parse().ok()).unwrap_or(0);
+        let y = attribute(element, "y").and_then(|y| y.parse().ok()).unwrap_or(0);
+
+        let name = attribute(element, "name")
+            .map(|name| name.to_string())
+            .unwrap_or(format!("Layer {}", i));
+        let mut layer = Layer::new(name, images.len());
+        layer.opacity = attribute(element, "opacity")
+            .and_then(|opacity| opacity.parse::<f32>().ok())
+            .map(|opacity| opacity.max(0.0).min(1.0))
+            .unwrap_or(1.0);
+        layer.visible = attribute(element, "visibility") != Some("hidden");
+
+        layers.push(layer);
+        images.push(place_on_canvas(image, x, y, width, height));
+    }
+    Ok((layers, images))
+}
+
+/*
+ * Writes `layers` (bottom to top) to an OpenRaster file. The canvas is as
+ * large as the largest layer.
+ */
+pub fn save_ora<P: AsRef<path::Path>>(path: P, layers: &[Layer], images: &[ImageBuffer]) -> io::Result<()> {
+    if let Some(layer) = layers.iter().find(|layer| layer.image_id >= images.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("layer `{}` refers to image {}, which doesn't exist", layer.name, layer.image_id)));
+    }
+    let width = layers.iter().map(|layer| images[layer.image_id].width).max().unwrap_or(0);
+    let height = layers.iter().map(|layer| images[layer.image_id].height).max().unwrap_or(0);
+
+    let mut zip = zip::ZipWriter::new(try!(fs::File::create(path)));
+    let stored = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    // The mimetype has to be the first, uncompressed entry
+    try!(zip.start_file("mimetype", stored).map_err(zip_error));
+    try!(zip.write_all(MIMETYPE.as_bytes()));
+
+    let mut stack = String::new();
+    stack = stack + "<?xml version='1.0' encoding='UTF-8'?>\n"
+        + &format!("<image version=\"0.0.5\" w=\"{}\" h=\"{}\">\n", width, height)
+        + "<stack>\n";
+    for (i, layer) in layers.iter().enumerate().rev() {
+        let src = format!("data/layer{}.png", i);
+        stack = stack + &format!(
+            "<layer name=\"{}\" src=\"{}\" x=\"0\" y=\"0\" opacity=\"{:.3}\" visibility=\"{}\"/>\n",
+            escape(&layer.name), src, layer.opacity,
+            if layer.visible { "visible" } else { "hidden" });
+
+        try!(zip.start_file(src, deflated).map_err(zip_error));
+        try!(images[layer.image_id].write_png(&mut zip, png::ColorType::RGBA));
+    }
+    stack = stack + "</stack>\n</image>\n";
+
+    try!(zip.start_file("stack.xml", deflated).map_err(zip_error));
+    try!(zip.write_all(stack.as_bytes()));
+
+    let merged = flatten(layers, images, width, height);
+    try!(zip.start_file("mergedimage.png", deflated).map_err(zip_error));
+    try!(merged.write_png(&mut zip, png::ColorType::RGBA));
+    try!(zip.start_file("Thumbnails/thumbnail.png", deflated).map_err(zip_error));
+    try!(thumbnail(&merged).write_png(&mut zip, png::ColorType::RGBA));
+
+    try!(zip.finish().map_err(zip_error));
+    Ok(())
+}
+
+/*
+ * Composites the visible layers, bottom to top, onto a transparent canvas
+ */
+pub fn flatten(layers: &[Layer], images: &[ImageBuffer], width: usize, height: usize) -> ImageBuffer {
+    let mut canvas = ImageBuffer::new(width, height);
+    for layer in layers.iter().filter(|layer| layer.visible) {
+        let image = &images[layer.image_id];
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let pixel = canvas.get_mut_ref(x, y);
+                *pixel = util::blend_over(*pixel, image.get_point(x, y), layer.opacity);
+            }
+        }
+    }
+    canvas
+}
+
+/*
+ * Nearest neighbour downscale so that neither side exceeds 256 pixels
+ */
+fn thumbnail(image: &ImageBuffer) -> ImageBuffer {
+    let largest = image.width.max(image.height);
+    if largest <= THUMBNAIL_MAX_PX {
+        let mut copy = ImageBuffer::new(image.width, image.height);
+        copy.buffer.clone_from(&image.buffer);
+        return copy;
+    }
+    let width = (image.width * THUMBNAIL_MAX_PX / largest).max(1);
+    let height = (image.height * THUMBNAIL_MAX_PX / largest).max(1);
+    let mut thumbnail = ImageBuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            *thumbnail.get_mut_ref(x, y) =
+                image.get_point(x * image.width / width, y * image.height / height);
+        }
+    }
+    thumbnail
+}
+
+/*
+ * Layers in an OpenRaster file may be smaller than the canvas and offset,
+ * while ours always cover the whole canvas.
+ */
+fn place_on_canvas(image: ImageBuffer, x: i64, y: i64, width: usize, height: usize) -> ImageBuffer {
+    if x == 0 && y == 0 && image.width == width && image.height == height {
+        return image;
+    }
+    let mut canvas = ImageBuffer::new(width, height);
+    for image_y in 0..image.height {
+        for image_x in 0..image.width {
+            let (canvas_x, canvas_y) = (image_x as i64 + x, image_y as i64 + y);
+            if canvas_x >= 0 && canvas_y >= 0
+                && (canvas_x as usize) < width && (canvas_y as usize) < height
+            {
+                *canvas.get_mut_ref(canvas_x as usize, canvas_y as usize) =
+                    image.get_point(image_x, image_y);
+            }
+        }
+    }
+    canvas
+}
+
+/*
+ * Finds every `<tag ...>` element in `xml` and returns its attributes.
+ * Nesting is ignored, this is just enough for stack.xml.
+ */
+fn find_elements(xml: &str, tag: &str) -> Vec<Vec<(String, String)>> {
+    let open = format!("<{}", tag);
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open[..]) {
+        let after = &rest[start + open.len()..];
+        let end = match after.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        if after.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            elements.push(parse_attributes(&after[..end]));
+        }
+        rest = &after[end..];
+    }
+    elements
+}
+
+fn parse_attributes(string: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = string;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim().to_string();
+        let value = rest[equals + 1..].trim_left();
+        let quote = match value.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => break,
+        };
+        let value = &value[1..];
+        let close = match value.find(quote) {
+            Some(close) => close,
+            None => break,
+        };
+        attributes.push((name, unescape(&value[..close])));
+        rest = &value[close + 1..];
+    }
+    attributes
+}
+
+/*
+ * The `w` or `h` of the `<image>` element, which has to be there
+ */
+fn canvas_side(image: &[(String, String)], name: &str) -> io::Result<usize> {
+    match attribute(image, name).and_then(|side| side.parse::<usize>().ok()) {
+        Some(side) if side >= 1 && side <= MAX_IMAGE_SIDE_PX => Ok(side),
+        Some(side) => Err(invalid_data(&format!(
+            "the canvas {} of {} isn't between 1 and {}", name, side, MAX_IMAGE_SIDE_PX))),
+        None => Err(invalid_data(&format!("<image> without a valid `{}` attribute", name))),
+    }
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes.iter()
+        .find(|&&(ref key, _)| key == name)
+        .map(|&(_, ref value)| &value[..])
+}
+
+fn escape(string: &str) -> String {
+    string.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(string: &str) -> String {
+    string.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn zip_error(error: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use image_buffer::ImageBuffer;
+    use state::Layer;
+    use super::{find_elements, parse_attributes, attribute, escape, unescape, canvas_side,
+                save_ora};
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(parse_attributes(" name=\"Sky\" x='3'  y = \"-2\" "),
+                   vec![pair("name", "Sky"), pair("x", "3"), pair("y", "-2")]);
+        assert_eq!(parse_attributes(" name=\"a &amp; b\"/"), vec![pair("name", "a & b")]);
+        assert_eq!(parse_attributes(" name=\"unterminated"), vec![]);
+        assert_eq!(parse_attributes(" name=unquoted"), vec![]);
+        assert_eq!(parse_attributes(""), vec![]);
+    }
+
+    #[test]
+    fn elements() {
+        let stack = "<?xml version='1.0'?>\n\
+                     <image w=\"4\" h=\"2\">\n<stack>\n\
+                     <layer name=\"top\" src=\"data/1.png\"/>\n\
+                     <layers-not-a-layer/>\n\
+                     <layer name=\"bottom\" src=\"data/0.png\" visibility=\"hidden\"/>\n\
+                     </stack>\n</image>\n";
+        let images = find_elements(stack, "image");
+        assert_eq!(images.len(), 1);
+        assert_eq!(attribute(&images[0], "w"), Some("4"));
+        assert_eq!(attribute(&images[0], "h"), Some("2"));
+
+        let layers = find_elements(stack, "layer");
+        assert_eq!(layers.len(), 2);
+        assert_eq!(attribute(&layers[0], "name"), Some("top"));
+        assert_eq!(attribute(&layers[1], "src"), Some("data/0.png"));
+        assert_eq!(attribute(&layers[1], "visibility"), Some("hidden"));
+        assert_eq!(attribute(&layers[1], "opacity"), None);
+
+        assert!(find_elements("<stack></stack>", "layer").is_empty());
+        assert!(find_elements("<layer name=\"cut off\"", "layer").is_empty());
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        for name in &["plain", "a & b", "<tag>", "\"quoted\"", "it's", "&amp; already"] {
+            assert_eq!(unescape(&escape(name)), *name);
+        }
+        assert_eq!(escape("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+        let element = format!(" name=\"{}\"", escape("\"x\" & 'y'"));
+        assert_eq!(parse_attributes(&element), vec![pair("name", "\"x\" & 'y'")]);
+    }
+
+    #[test]
+    fn canvas_sides() {
+        let image = parse_attributes(" w=\"64\" h=\"0\" x=\"big\" y=\"100000\"");
+        assert_eq!(canvas_side(&image, "w").unwrap(), 64);
+        assert!(canvas_side(&image, "h").is_err());
+        assert!(canvas_side(&image, "x").is_err());
+        assert!(canvas_side(&image, "y").is_err());
+        assert!(canvas_side(&image, "missing").is_err());
+    }
+
+    #[test]
+    fn saving_checks_image_ids() {
+        let path = env::temp_dir().join("rusted_pixels_stale_layer_test.ora");
+        let layers = vec![Layer::new(String::from("stale"), 1)];
+        assert!(save_ora(&path, &layers, &[ImageBuffer::new(2, 2)]).is_err());
+        assert!(!path.exists());
+    }
+}
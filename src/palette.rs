@@ -0,0 +1,313 @@
+use png;
+use sdl2::pixels::Color;
+use std::{fs,io,path};
+use std::io::{Read,Write};
+
+use image_buffer::ImageBuffer;
+use util;
+
+/*
+ * Reading and writing palette files. Loading detects the format by
+ * content, saving picks it from the file extension.
+ *
+ * Supported: GIMP (.gpl), JASC (.pal), Lospec (.hex), Photoshop (.act)
+ * and PNG strips where every unique color is a palette entry.
+ */
+
+/*
+ * A named list of colors
+ */
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<Color>,
+}
+
+impl Palette {
+    pub fn new(name: String, colors: Vec<Color>) -> Self {
+        Palette {
+            name: name,
+            colors: colors,
+        }
+    }
+}
+
+const PNG_SIGNATURE: &'static [u8] = b"\x89PNG\r\n\x1a\n";
+const ACT_COLORS: usize = 256;
+
+pub fn load_palette<P: AsRef<path::Path>>(path: P) -> io::Result<Vec<Color>> {
+    let mut data = Vec::new();
+    try!(try!(fs::File::open(path)).read_to_end(&mut data));
+
+    if data.starts_with(PNG_SIGNATURE) {
+        return read_png_strip(&data);
+    }
+    if let Ok(text) = ::std::str::from_utf8(&data) {
+        let trimmed = text.trim_left();
+        if trimmed.starts_with("GIMP Palette") {
+            return read_gpl(trimmed);
+        }
+        if trimmed.starts_with("JASC-PAL") {
+            return read_jasc(trimmed);
+        }
+        if is_hex(trimmed) {
+            return read_hex(trimmed);
+        }
+    }
+    if data.len() == ACT_COLORS * 3 || data.len() == ACT_COLORS * 3 + 4 {
+        return Ok(read_act(&data));
+    }
+    Err(invalid_data("unrecognized palette format"))
+}
+
+pub fn save_palette<P: AsRef<path::Path>>(path: P, colors: &[Color]) -> io::Result<()> {
+    let extension = path.as_ref().extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .unwrap_or(String::new());
+    let data = match &extension[..] {
+        "gpl" => write_gpl(colors).into_bytes(),
+        "pal" => write_jasc(colors).into_bytes(),
+        "hex" => write_hex(colors).into_bytes(),
+        "act" => try!(write_act(colors)),
+        "png" => try!(write_png_strip(colors)),
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unknown palette extension, expected gpl, pal, hex, act or png")),
+    };
+    let mut file = try!(fs::File::create(path));
+    try!(file.write_all(&data));
+    Ok(())
+}
+
+fn read_gpl(text: &str) -> io::Result<Vec<Color>> {
+    let mut colors = Vec::new();
+    for line in text.lines().skip(1) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#')
+            || line.starts_with("Name:") || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        // `r g b name`, the name is optional
+        let components = line.split_whitespace()
+            .take(3)
+            .map(|component| component.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>();
+        match components {
+            Ok(ref rgb) if rgb.len() == 3 => colors.push(Color::RGB(rgb[0], rgb[1], rgb[2])),
+            _ => return Err(invalid_data(&format!("invalid GIMP palette entry `{}`", line))),
+        }
+    }
+    Ok(colors)
+}
+
+fn write_gpl(colors: &[Color]) -> String {
+    let mut string = String::from("GIMP Palette\nName: rusted pixels\nColumns: 8\n#\n");
+    for color in colors {
+        let (r,g,b,_) = util::color_to_rgba(*color);
+        string = string + &format!("{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}\n", r, g, b, r, g, b);
+    }
+    string
+}
+
+fn read_jasc(text: &str) -> io::Result<Vec<Color>> {
+    let mut lines = text.lines().map(|line| line.trim());
+    lines.next(); // JASC-PAL
+    lines.next(); // version, always 0100
+    let count = match lines.next().and_then(|count| count.parse::<usize>().ok()) {
+        Some(count) => count,
+        None => return Err(invalid_data("JASC palette without a color count")),
+    };
+    let mut colors = Vec::with_capacity(count);
+    for line in lines.filter(|line| !line.is_empty()).take(count) {
+        let components = line.split_whitespace()
+            .map(|component| component.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>();
+        match components {
+            Ok(ref rgb) if rgb.len() >= 3 => colors.push(Color::RGB(rgb[0], rgb[1], rgb[2])),
+            _ => return Err(invalid_data(&format!("invalid JASC palette entry `{}`", line))),
+        }
+    }
+    Ok(colors)
+}
+
+fn write_jasc(colors: &[Color]) -> String {
+    let mut string = format!("JASC-PAL\r\n0100\r\n{}\r\n", colors.len());
+    for color in colors {
+        let (r,g,b,_) = util::color_to_rgba(*color);
+        string = string + &format!("{} {} {}\r\n", r, g, b);
+    }
+    string
+}
+
+fn is_hex(text: &str) -> bool {
+    let mut lines = text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .peekable();
+    lines.peek().is_some() && lines.all(|line| parse_hex_line(line).is_some())
+}
+
+fn parse_hex_line(line: &str) -> Option<Color> {
+    let line = if line.starts_with('#') { &line[1..] } else { line };
+    if line.len() != 6 || !line.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(&line[i..i + 2], 16).ok();
+    match (component(0), component(2), component(4)) {
+        (Some(r), Some(g), Some(b)) => Some(Color::RGB(r,g,b)),
+        _ => None,
+    }
+}
+
+fn read_hex(text: &str) -> io::Result<Vec<Color>> {
+    Ok(text.lines()
+       .map(|line| line.trim())
+       .filter_map(parse_hex_line)
+       .collect())
+}
+
+fn write_hex(colors: &[Color]) -> String {
+    let mut string = String::new();
+    for color in colors {
+        let (r,g,b,_) = util::color_to_rgba(*color);
+        string = string + &format!("{:02x}{:02x}{:02x}\n", r, g, b);
+    }
+    string
+}
+
+/*
+ * 256 RGB triplets, optionally followed by a big endian color count and
+ * transparent index.
+ */
+fn read_act(data: &[u8]) -> Vec<Color> {
+    let count = if data.len() == ACT_COLORS * 3 + 4 {
+        let count = ((data[ACT_COLORS * 3] as usize) << 8) | data[ACT_COLORS * 3 + 1] as usize;
+        if count == 0 || count > ACT_COLORS { ACT_COLORS } else { count }
+    } else {
+        ACT_COLORS
+    };
+    data[..count * 3].chunks(3)
+        .map(|rgb| Color::RGB(rgb[0], rgb[1], rgb[2]))
+        .collect()
+}
+
+fn write_act(colors: &[Color]) -> io::Result<Vec<u8>> {
+    if colors.len() > ACT_COLORS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ACT palettes hold at most 256 colors"));
+    }
+    let mut data = Vec::with_capacity(ACT_COLORS * 3 + 4);
+    for color in colors {
+        let (r,g,b,_) = util::color_to_rgba(*color);
+        data.extend_from_slice(&[r,g,b]);
+    }
+    data.resize(ACT_COLORS * 3, 0);
+    data.extend_from_slice(&[(colors.len() >> 8) as u8, colors.len() as u8, 0xff, 0xff]);
+    Ok(data)
+}
+
+fn read_png_strip(data: &[u8]) -> io::Result<Vec<Color>> {
+    let image = try!(ImageBuffer::read_png(data));
+    let mut colors: Vec<Color> = Vec::new();
+    for color in &image.buffer {
+        if !colors.iter().any(|known| util::color_to_rgba(*known) == util::color_to_rgba(*color)) {
+            colors.push(*color);
+        }
+    }
+    Ok(colors)
+}
+
+fn write_png_strip(colors: &[Color]) -> io::Result<Vec<u8>> {
+    let mut image = ImageBuffer::new(colors.len(), 1);
+    image.buffer = colors.to_vec();
+    let mut data = Vec::new();
+    try!(image.write_png(&mut data, png::ColorType::RGBA));
+    Ok(data)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env,fs};
+    use sdl2::pixels::Color;
+    use util::color_to_rgba;
+    use super::{load_palette, save_palette, read_gpl, write_gpl, read_jasc, write_jasc, read_hex, write_hex,
+                read_act, write_act, read_png_strip, write_png_strip, is_hex};
+
+    fn colors() -> Vec<Color> {
+        vec![Color::RGB(0,0,0), Color::RGB(255,128,1), Color::RGB(18,52,86)]
+    }
+
+    fn rgba(colors: &[Color]) -> Vec<(u8,u8,u8,u8)> {
+        colors.iter().map(|color| color_to_rgba(*color)).collect()
+    }
+
+    #[test]
+    fn gpl() {
+        assert_eq!(rgba(&read_gpl(&write_gpl(&colors())).unwrap()), rgba(&colors()));
+        let text = "GIMP Palette\nName: test\nColumns: 2\n# comment\n\n  1   2   3\tUntitled\n255 0 9\n";
+        assert_eq!(rgba(&read_gpl(text).unwrap()), rgba(&[Color::RGB(1,2,3), Color::RGB(255,0,9)]));
+        assert!(read_gpl("GIMP Palette\n1 2\n").is_err());
+        assert!(read_gpl("GIMP Palette\n1 2 256\n").is_err());
+    }
+
+    #[test]
+    fn jasc() {
+        assert_eq!(rgba(&read_jasc(&write_jasc(&colors())).unwrap()), rgba(&colors()));
+        // the count limits how many entries are read
+        let text = "JASC-PAL\n0100\n1\n4 5 6\n7 8 9\n";
+        assert_eq!(rgba(&read_jasc(text).unwrap()), rgba(&[Color::RGB(4,5,6)]));
+        assert!(read_jasc("JASC-PAL\n0100\n").is_err());
+        assert!(read_jasc("JASC-PAL\n0100\n1\n4 5\n").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        let written = write_hex(&colors());
+        assert_eq!(written, "000000\nff8001\n123456\n");
+        assert!(is_hex(&written));
+        assert_eq!(rgba(&read_hex(&written).unwrap()), rgba(&colors()));
+        assert!(is_hex("#ff0000\n\n00FF00\n"));
+        assert!(!is_hex("ff00000\n"));
+        assert!(!is_hex("GIMP Palette\n"));
+        assert!(!is_hex(""));
+    }
+
+    #[test]
+    fn act() {
+        let data = write_act(&colors()).unwrap();
+        assert_eq!(data.len(), 256 * 3 + 4);
+        assert_eq!(&data[256 * 3..], &[0, 3, 0xff, 0xff]);
+        assert_eq!(rgba(&read_act(&data)), rgba(&colors()));
+        // without the count all 256 colors are there
+        assert_eq!(read_act(&data[..256 * 3]).len(), 256);
+        assert!(write_act(&vec![Color::RGB(0,0,0); 257]).is_err());
+    }
+
+    #[test]
+    fn png_strip() {
+        let mut with_duplicates = colors();
+        with_duplicates.push(Color::RGBA(0,0,0,255));
+        let data = write_png_strip(&with_duplicates).unwrap();
+        assert_eq!(rgba(&read_png_strip(&data).unwrap()),
+                   rgba(&[Color::RGBA(0,0,0,255), Color::RGBA(255,128,1,255),
+                          Color::RGBA(18,52,86,255)]));
+    }
+
+    #[test]
+    fn save_and_load_by_extension() {
+        for extension in &["gpl", "pal", "hex", "act", "png"] {
+            let path = env::temp_dir().join(format!("rusted_pixels_palette_test.{}", extension));
+            save_palette(&path, &colors()).unwrap();
+            assert_eq!(rgba(&load_palette(&path).unwrap()), rgba(&colors()));
+            fs::remove_file(&path).unwrap();
+        }
+        let path = env::temp_dir().join("rusted_pixels_palette_test.txt");
+        assert!(save_palette(&path, &colors()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use sdl2::pixels::Color;
+
+use color_space;
+use util;
+
+/*
+ * Generators producing palettes from a few parameters. Ramps include
+ * both end points.
+ */
+
+// More colors than a palette would ever hold, and few enough that asking
+// for them can't use up the memory
+pub const MAX_RAMP_STEPS: usize = 256;
+
+/*
+ * Interpolates each sRGB channel
+ */
+pub fn linear_ramp(from: Color, to: Color, steps: usize) -> Vec<Color> {
+    let (r1,g1,b1,a1) = util::color_to_rgba(from);
+    let (r2,g2,b2,a2) = util::color_to_rgba(to);
+    ramp_positions(steps).into_iter().map(|t| {
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        let alpha = lerp(a1, a2);
+        if alpha == 255 {
+            Color::RGB(lerp(r1,r2), lerp(g1,g2), lerp(b1,b2))
+        } else {
+            Color::RGBA(lerp(r1,r2), lerp(g1,g2), lerp(b1,b2), alpha)
+        }
+    }).collect()
+}
+
+/*
+ * Interpolates hue (the short way around), saturation and value
+ */
+pub fn hsv_ramp(from: Color, to: Color, steps: usize) -> Vec<Color> {
+    let (h1,s1,v1) = color_space::to_hsv(from);
+    let (h2,s2,v2) = color_space::to_hsv(to);
+    // grays have no meaningful hue, borrow the other end's
+    let h1 = if s1 == 0.0 { h2 } else { h1 };
+    let h2 = if s2 == 0.0 { h1 } else { h2 };
+    let mut hue_delta = h2 - h1;
+    if hue_delta > 180.0 {
+        hue_delta -= 360.0;
+    } else if hue_delta < -180.0 {
+        hue_delta += 360.0;
+    }
+    let alpha = util::color_to_rgba(from).3;
+    ramp_positions(steps).into_iter().map(|t| {
+        color_space::from_hsv(h1 + hue_delta * t,
+                              s1 + (s2 - s1) * t,
+                              v1 + (v2 - v1) * t,
+                              alpha)
+    }).collect()
+}
+
+/*
+ * Interpolates in OKLab, giving perceptually even steps
+ */
+pub fn oklab_ramp(from: Color, to: Color, steps: usize) -> Vec<Color> {
+    let (l1,a1,b1) = color_space::to_oklab(from);
+    let (l2,a2,b2) = color_space::to_oklab(to);
+    let alpha = util::color_to_rgba(from).3;
+    ramp_positions(steps).into_iter().map(|t| {
+        color_space::from_oklab(l1 + (l2 - l1) * t,
+                                a1 + (a2 - a1) * t,
+                                b1 + (b2 - b1) * t,
+                                alpha)
+    }).collect()
+}
+
+/*
+ * A dark to light shading ramp around `base`. The hue rotates by
+ * `hue_shift` degrees from the darkest to the lightest tone, so that
+ * shadows and highlights lean towards different hues. `saturation_curve`
+ * (0..1) is how much saturation drops towards both ends of the ramp,
+ * keeping the midtones the most saturated.
+ */
+pub fn shading_ramp(base: Color, steps: usize, hue_shift: f32, saturation_curve: f32) -> Vec<Color> {
+    let (hue, saturation, value) = color_space::to_hsv(base);
+    let alpha = util::color_to_rgba(base).3;
+    ramp_positions(steps).into_iter().map(|t| {
+        // -1 is the darkest tone, 0 the base and 1 the lightest
+        let t = t * 2.0 - 1.0;
+        let tone_value = if t < 0.0 {
+            value * (1.0 + t * 0.85)
+        } else {
+            value + (1.0 - value) * t * 0.85
+        };
+        color_space::from_hsv(hue + hue_shift * t / 2.0,
+                              saturation * (1.0 - saturation_curve * t * t),
+                              tone_value,
+                              alpha)
+    }).collect()
+}
+
+pub fn complementary(base: Color) -> Vec<Color> {
+    rotations(base, &[0.0, 180.0])
+}
+
+pub fn triadic(base: Color) -> Vec<Color> {
+    rotations(base, &[0.0, 120.0, 240.0])
+}
+
+fn rotations(base: Color, degrees: &[f32]) -> Vec<Color> {
+    let (hue, saturation, value) = color_space::to_hsv(base);
+    let alpha = util::color_to_rgba(base).3;
+    degrees.iter()
+        .map(|degree| color_space::from_hsv(hue + degree, saturation, value, alpha))
+        .collect()
+}
+
+/*
+ * `steps` evenly spaced points from 0 to 1, both included
+ */
+fn ramp_positions(steps: usize) -> Vec<f32> {
+    match steps {
+        0 => vec![],
+        1 => vec![0.5],
+        _ => (0..steps).map(|i| i as f32 / (steps - 1) as f32).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use color_space;
+    use util::color_to_rgba;
+    use super::{linear_ramp, hsv_ramp, oklab_ramp, shading_ramp, complementary, triadic};
+
+    fn assert_close(a: Color, b: Color) {
+        let (a, b) = (color_to_rgba(a), color_to_rgba(b));
+        let near = |x: u8, y: u8| (x as i32 - y as i32).abs() <= 1;
+        assert!(near(a.0, b.0) && near(a.1, b.1) && near(a.2, b.2) && a.3 == b.3,
+                "{:?} isn't close to {:?}", a, b);
+    }
+
+    // Hues of dark tones only have a few levels of each channel to go on
+    fn assert_hue(color: Color, hue: f32) {
+        let actual = color_space::to_hsv(color).0;
+        let difference = (actual - hue + 540.0) % 360.0 - 180.0;
+        assert!(difference.abs() < 4.0, "hue {} isn't near {}", actual, hue);
+    }
+
+    #[test]
+    fn ramps_end_at_their_colors() {
+        let (from, to) = (Color::RGB(20,40,160), Color::RGB(250,220,90));
+        let generators: [fn(Color, Color, usize) -> Vec<Color>; 3] =
+            [linear_ramp, hsv_ramp, oklab_ramp];
+        for generator in generators.iter() {
+            for &steps in &[2, 5, 16] {
+                let ramp = generator(from, to, steps);
+                assert_eq!(ramp.len(), steps);
+                assert_close(ramp[0], from);
+                assert_close(ramp[steps - 1], to);
+            }
+        }
+        let ramp = linear_ramp(Color::RGB(0,0,0), Color::RGBA(255,255,255,0), 3);
+        assert_eq!(ramp.iter().map(|color| color_to_rgba(*color)).collect::<Vec<_>>(),
+                   vec![(0,0,0,255), (128,128,128,128), (255,255,255,0)]);
+    }
+
+    #[test]
+    fn hsv_ramps_take_the_short_way_around() {
+        let ramp = hsv_ramp(color_space::from_hsv(350.0, 1.0, 1.0, 255),
+                            color_space::from_hsv(30.0, 1.0, 1.0, 255), 3);
+        assert_hue(ramp[1], 10.0);
+    }
+
+    #[test]
+    fn shading_shifts_hue_from_dark_to_light() {
+        let base = color_space::from_hsv(200.0, 1.0, 0.8, 255);
+        let ramp = shading_ramp(base, 5, 40.0, 0.0);
+        assert_eq!(ramp.len(), 5);
+        assert_hue(ramp[0], 180.0);
+        assert_hue(ramp[2], 200.0);
+        assert_hue(ramp[4], 220.0);
+        assert_close(ramp[2], base);
+        // darkest to lightest
+        let values: Vec<f32> = ramp.iter().map(|color| color_space::to_hsv(*color).2).collect();
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", values);
+
+        assert_close(shading_ramp(base, 1, 40.0, 0.5)[0], base);
+        assert!(shading_ramp(base, 0, 40.0, 0.5).is_empty());
+    }
+
+    #[test]
+    fn schemes() {
+        let base = color_space::from_hsv(30.0, 1.0, 1.0, 255);
+        let colors = complementary(base);
+        assert_eq!(colors.len(), 2);
+        assert_hue(colors[1], 210.0);
+        let colors = triadic(base);
+        assert_eq!(colors.len(), 3);
+        assert_hue(colors[1], 150.0);
+        assert_hue(colors[2], 270.0);
+    }
+}
@@ -0,0 +1,445 @@
+use sdl2::pixels::Color;
+use std::collections::HashMap;
+
+use color_space;
+use image_buffer::ImageBuffer;
+use util;
+
+/*
+ * Color reduction: finding the colors used by an image and reducing them
+ * to a smaller set.
+ */
+
+const KMEANS_ITERATIONS: usize = 8;
+// How far ordered dithering may push the lightness of a pixel
+const BAYER_STRENGTH: f32 = 0.12;
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [ 0,  8,  2, 10],
+    [12,  4, 14,  6],
+    [ 3, 11,  1,  9],
+    [15,  7, 13,  5],
+];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorOrder {
+    Usage,
+    Hue,
+    Luminance,
+}
+
+impl ColorOrder {
+    pub fn from_name(name: &str) -> Option<ColorOrder> {
+        match name {
+            "usage" => Some(ColorOrder::Usage),
+            "hue" => Some(ColorOrder::Hue),
+            "luminance" => Some(ColorOrder::Luminance),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Bayer,
+}
+
+impl Dither {
+    pub fn from_name(name: &str) -> Option<Dither> {
+        match name {
+            "none" => Some(Dither::None),
+            "floyd-steinberg" => Some(Dither::FloydSteinberg),
+            "atkinson" => Some(Dither::Atkinson),
+            "bayer" => Some(Dither::Bayer),
+            _ => None,
+        }
+    }
+
+    /*
+     * Error diffusion kernel as `(dx, dy, weight)`
+     */
+    fn kernel(&self) -> &'static [(isize, isize, f32)] {
+        const FLOYD_STEINBERG: &'static [(isize, isize, f32)] = &[
+            (1, 0, 7.0 / 16.0),
+            (-1, 1, 3.0 / 16.0), (0, 1, 5.0 / 16.0), (1, 1, 1.0 / 16.0),
+        ];
+        const ATKINSON: &'static [(isize, isize, f32)] = &[
+            (1, 0, 1.0 / 8.0), (2, 0, 1.0 / 8.0),
+            (-1, 1, 1.0 / 8.0), (0, 1, 1.0 / 8.0), (1, 1, 1.0 / 8.0),
+            (0, 2, 1.0 / 8.0),
+        ];
+        match *self {
+            Dither::FloydSteinberg => FLOYD_STEINBERG,
+            Dither::Atkinson => ATKINSON,
+            Dither::None | Dither::Bayer => &[],
+        }
+    }
+}
+
+/*
+ * Maps every pixel of `image` to the perceptually nearest color of
+ * `palette` (in OKLab), optionally dithering. Alpha is kept as is and
+ * fully transparent pixels are left untouched. Returns the new pixels.
+ */
+pub fn remap(image: &ImageBuffer, palette: &[Color], dither: Dither) -> Vec<Color> {
+    if palette.is_empty() {
+        return image.buffer.clone();
+    }
+    let palette_labs: Vec<_> = palette.iter().map(|&color| color_space::to_oklab(color)).collect();
+    let mut labs: Vec<_> = image.buffer.iter().map(|&color| color_space::to_oklab(color)).collect();
+    let mut out = image.buffer.clone();
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let index = y * image.width + x;
+            let alpha = util::color_to_rgba(image.buffer[index]).3;
+            if alpha == 0 {
+                continue;
+            }
+            let mut lab = labs[index];
+            if dither == Dither::Bayer {
+                let threshold = BAYER_4X4[y % 4][x % 4] as f32 / 16.0 - 0.5;
+                lab.0 += threshold * BAYER_STRENGTH;
+            }
+            let chosen = nearest(&palette_labs, lab);
+            let (r,g,b,_) = util::color_to_rgba(palette[chosen]);
+            out[index] = if alpha == 255 { Color::RGB(r,g,b) } else { Color::RGBA(r,g,b,alpha) };
+
+            let chosen_lab = palette_labs[chosen];
+            let error = (lab.0 - chosen_lab.0, lab.1 - chosen_lab.1, lab.2 - chosen_lab.2);
+            for &(dx, dy, weight) in dither.kernel() {
+                let (nx, ny) = (x as isize + dx, y as isize + dy);
+                if nx < 0 || nx >= image.width as isize || ny >= image.height as isize {
+                    continue;
+                }
+                let neighbour = &mut labs[ny as usize * image.width + nx as usize];
+                neighbour.0 += error.0 * weight;
+                neighbour.1 += error.1 * weight;
+                neighbour.2 += error.2 * weight;
+            }
+        }
+    }
+    out
+}
+
+/*
+ * Builds a palette from the colors of `image`, ignoring fully transparent
+ * pixels. If it uses more than `max_colors` colors they're reduced with
+ * median cut, refined with a few rounds of k-means.
+ */
+pub fn extract_palette(image: &ImageBuffer, max_colors: usize, order: ColorOrder) -> Vec<Color> {
+    let histogram: Vec<_> = color_histogram(image).into_iter()
+        .filter(|&(color, _)| util::color_to_rgba(color).3 > 0)
+        .collect();
+    let mut colors = if histogram.len() <= max_colors {
+        histogram
+    } else {
+        kmeans(&histogram, median_cut(&histogram, max_colors))
+    };
+
+    match order {
+        ColorOrder::Usage => colors.sort_by(|a, b| b.1.cmp(&a.1)),
+        ColorOrder::Hue => colors.sort_by(|a, b| {
+            let (hue_a, saturation_a, value_a) = color_space::to_hsv(a.0);
+            let (hue_b, saturation_b, value_b) = color_space::to_hsv(b.0);
+            // grays first, then by hue and value
+            (saturation_a > 0.0, hue_a, value_a)
+                .partial_cmp(&(saturation_b > 0.0, hue_b, value_b))
+                .unwrap()
+        }),
+        ColorOrder::Luminance => colors.sort_by(|a, b| {
+            color_space::to_oklab(a.0).0
+                .partial_cmp(&color_space::to_oklab(b.0).0)
+                .unwrap()
+        }),
+    }
+    colors.into_iter().map(|(color, _)| color).collect()
+}
+
+/*
+ * Every unique color of `image` with its number of pixels, in order of
+ * first appearance
+ */
+pub fn color_histogram(image: &ImageBuffer) -> Vec<(Color, usize)> {
+    let mut indices: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    let mut histogram: Vec<(Color, usize)> = Vec::new();
+    for color in &image.buffer {
+        let key = util::color_to_rgba(*color);
+        if let Some(&index) = indices.get(&key) {
+            histogram[index].1 += 1;
+            continue;
+        }
+        indices.insert(key, histogram.len());
+        histogram.push((*color, 1));
+    }
+    histogram
+}
+
+/*
+ * Splits the colors into `max_colors` boxes by repeatedly cutting the box
+ * with the widest channel range at its weighted median, and returns the
+ * weighted average of each box.
+ */
+pub fn median_cut(histogram: &[(Color, usize)], max_colors: usize) -> Vec<Color> {
+    let mut boxes: Vec<Vec<((u8, u8, u8, u8), usize)>> = vec![
+        histogram.iter().map(|&(color, count)| (util::color_to_rgba(color), count)).collect()
+    ];
+
+    while boxes.len() < max_colors {
+        // find the box and channel with the widest range
+        let mut widest = None;
+        for (i, colors) in boxes.iter().enumerate() {
+            if colors.len() < 2 {
+                continue;
+            }
+            for channel in 0..4 {
+                let values: Vec<u8> = colors.iter().map(|&(rgba, _)| component(rgba, channel)).collect();
+                let range = values.iter().max().unwrap() - values.iter().min().unwrap();
+                match widest {
+                    Some((_, _, widest_range)) if widest_range >= range => {},
+                    _ => widest = Some((i, channel, range)),
+                }
+            }
+        }
+        let (index, channel) = match widest {
+            Some((index, channel, range)) if range > 0 => (index, channel),
+            _ => break,
+        };
+
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_by_key(|&(rgba, _)| component(rgba, channel));
+        let total: usize = colors.iter().map(|&(_, count)| count).sum();
+        let mut seen = 0;
+        let mut split = 1;
+        for (i, &(_, count)) in colors.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= total {
+                split = (i + 1).max(1).min(colors.len() - 1);
+                break;
+            }
+        }
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|colors| average(colors.iter().cloned())).collect()
+}
+
+/*
+ * Moves each center to the weighted average of the colors closest to it
+ * in OKLab, and returns the centers with the number of pixels they got
+ */
+pub fn kmeans(histogram: &[(Color, usize)], centers: Vec<Color>) -> Vec<(Color, usize)> {
+    let labs: Vec<_> = histogram.iter().map(|&(color, _)| color_space::to_oklab(color)).collect();
+    let mut centers = centers;
+    let mut assignments = vec![0; histogram.len()];
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let center_labs: Vec<_> = centers.iter().map(|&color| color_space::to_oklab(color)).collect();
+        for (i, lab) in labs.iter().enumerate() {
+            assignments[i] = nearest(&center_labs, *lab);
+        }
+        let mut changed = false;
+        for (center_index, center) in centers.iter_mut().enumerate() {
+            let members: Vec<_> = histogram.iter()
+                .zip(assignments.iter())
+                .filter(|&(_, &assigned)| assigned == center_index)
+                .map(|(&(color, count), _)| (util::color_to_rgba(color), count))
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let moved = average(members.into_iter());
+            if util::color_to_rgba(moved) != util::color_to_rgba(*center) {
+                *center = moved;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut counts = vec![0; centers.len()];
+    for (&(_, count), &assigned) in histogram.iter().zip(assignments.iter()) {
+        counts[assigned] += count;
+    }
+    centers.into_iter()
+        .zip(counts.into_iter())
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
+/*
+ * Index of the color in `palette` closest to `lab`
+ */
+pub fn nearest(palette: &[(f32, f32, f32)], lab: (f32, f32, f32)) -> usize {
+    let mut best = 0;
+    let mut best_distance = ::std::f32::MAX;
+    for (i, candidate) in palette.iter().enumerate() {
+        let distance = color_space::oklab_distance(*candidate, lab);
+        if distance < best_distance {
+            best = i;
+            best_distance = distance;
+        }
+    }
+    best
+}
+
+fn component(rgba: (u8, u8, u8, u8), channel: usize) -> u8 {
+    match channel {
+        0 => rgba.0,
+        1 => rgba.1,
+        2 => rgba.2,
+        _ => rgba.3,
+    }
+}
+
+fn average<I: Iterator<Item=((u8, u8, u8, u8), usize)>>(colors: I) -> Color {
+    let (mut r, mut g, mut b, mut a, mut total) = (0, 0, 0, 0, 0);
+    for ((cr, cg, cb, ca), count) in colors {
+        r += cr as usize * count;
+        g += cg as usize * count;
+        b += cb as usize * count;
+        a += ca as usize * count;
+        total += count;
+    }
+    if total == 0 {
+        return Color::RGBA(0,0,0,0);
+    }
+    let (r, g, b, a) = ((r / total) as u8, (g / total) as u8, (b / total) as u8, (a / total) as u8);
+    if a == 255 { Color::RGB(r,g,b) } else { Color::RGBA(r,g,b,a) }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use image_buffer::ImageBuffer;
+    use util::color_to_rgba;
+    use super::{ColorOrder, Dither, color_histogram, extract_palette, median_cut, kmeans, remap};
+
+    /*
+     * A one pixel high image with each color repeated `count` times
+     */
+    fn image_of(colors: &[(Color, usize)]) -> ImageBuffer {
+        let mut buffer = vec![];
+        for &(color, count) in colors {
+            for _ in 0..count {
+                buffer.push(color);
+            }
+        }
+        ImageBuffer::from_buffer(buffer.len(), 1, buffer)
+    }
+
+    fn rgba(colors: &[Color]) -> Vec<(u8, u8, u8, u8)> {
+        colors.iter().map(|&color| color_to_rgba(color)).collect()
+    }
+
+    fn gradient() -> ImageBuffer {
+        let colors: Vec<_> = (0..64usize)
+            .map(|i| {
+                let color = Color::RGB((i * 4) as u8, (255 - i * 4) as u8, (i * 37 % 256) as u8);
+                (color, 1 + i % 3)
+            })
+            .collect();
+        image_of(&colors)
+    }
+
+    #[test]
+    fn reduces_to_max_colors() {
+        let image = gradient();
+        let histogram = color_histogram(&image);
+        assert_eq!(histogram.len(), 64);
+
+        let centers = median_cut(&histogram, 8);
+        assert_eq!(centers.len(), 8);
+        let clusters = kmeans(&histogram, centers);
+        assert!(!clusters.is_empty() && clusters.len() <= 8);
+        let pixels: usize = clusters.iter().map(|&(_, count)| count).sum();
+        assert_eq!(pixels, image.buffer.len());
+
+        for &max_colors in &[1, 5, 16] {
+            let palette = extract_palette(&image, max_colors, ColorOrder::Usage);
+            assert!(!palette.is_empty() && palette.len() <= max_colors);
+        }
+    }
+
+    #[test]
+    fn few_colors_are_kept_exactly() {
+        let image = image_of(&[
+            (Color::RGB(10,20,30), 1),
+            (Color::RGBA(0,0,0,0), 4),
+            (Color::RGBA(200,100,50,128), 3),
+            (Color::RGB(255,255,255), 2),
+        ]);
+        // transparent pixels don't count as a color
+        for &max_colors in &[3, 8] {
+            let palette = extract_palette(&image, max_colors, ColorOrder::Usage);
+            assert_eq!(rgba(&palette), vec![(200,100,50,128), (255,255,255,255), (10,20,30,255)]);
+        }
+        // median cut can't split a box of one color
+        let single = image_of(&[(Color::RGB(1,2,3), 5)]);
+        assert_eq!(rgba(&median_cut(&color_histogram(&single), 4)), vec![(1,2,3,255)]);
+    }
+
+    #[test]
+    fn orderings() {
+        let image = image_of(&[
+            (Color::RGB(0,0,255), 1),
+            (Color::RGB(128,128,128), 2),
+            (Color::RGB(255,0,0), 5),
+            (Color::RGB(0,0,0), 3),
+            (Color::RGB(0,255,0), 4),
+        ]);
+        let ordered = |order| rgba(&extract_palette(&image, 8, order));
+
+        // most used first
+        assert_eq!(ordered(ColorOrder::Usage), vec![
+            (255,0,0,255), (0,255,0,255), (0,0,0,255), (128,128,128,255), (0,0,255,255),
+        ]);
+        // grays by value, then colors by hue
+        assert_eq!(ordered(ColorOrder::Hue), vec![
+            (0,0,0,255), (128,128,128,255), (255,0,0,255), (0,255,0,255), (0,0,255,255),
+        ]);
+        // darkest first
+        assert_eq!(ordered(ColorOrder::Luminance), vec![
+            (0,0,0,255), (0,0,255,255), (128,128,128,255), (255,0,0,255), (0,255,0,255),
+        ]);
+    }
+
+    #[test]
+    fn remap_picks_the_nearest_color() {
+        let palette = [Color::RGB(0,0,0), Color::RGB(255,255,255), Color::RGB(220,0,0)];
+        let image = ImageBuffer::from_buffer(3, 2, vec![
+            Color::RGB(30,30,30), Color::RGB(240,240,240), Color::RGB(180,30,20),
+            Color::RGBA(250,250,250,100), Color::RGBA(200,0,0,0), Color::RGBA(7,8,9,0),
+        ]);
+        assert_eq!(rgba(&remap(&image, &palette, Dither::None)), vec![
+            (0,0,0,255), (255,255,255,255), (220,0,0,255),
+            // alpha is kept and fully transparent pixels aren't touched
+            (255,255,255,100), (200,0,0,0), (7,8,9,0),
+        ]);
+        // no palette, no change
+        assert_eq!(rgba(&remap(&image, &[], Dither::None)), rgba(&image.buffer));
+    }
+
+    #[test]
+    fn dithers() {
+        for &name in &["none", "floyd-steinberg", "atkinson", "bayer"] {
+            let dither = Dither::from_name(name).unwrap();
+            // colors of the palette stay as they are, dithered or not
+            let palette = [Color::RGB(0,0,0), Color::RGB(255,255,255)];
+            let image = ImageBuffer::from_buffer(2, 2, vec![
+                Color::RGB(0,0,0), Color::RGB(255,255,255),
+                Color::RGB(255,255,255), Color::RGBA(0,0,0,0),
+            ]);
+            assert_eq!(rgba(&remap(&image, &palette, dither)), rgba(&image.buffer));
+        }
+        assert!(Dither::from_name("").is_none());
+        assert!(Dither::from_name("Bayer").is_none());
+        assert!(Dither::from_name("ordered").is_none());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use input::{self, Input, Command, CommandResult, ArgType};
+use macros;
+use state::{State, MessageLevel};
+use windows::Window;
+
+/*
+ * Runs a script of commands, one per line as `M-x` would take them:
+ *
+ *     # comments and blank lines are skipped
+ *     new-image 16 16
+ *     set-color #ff0000
+ *     fill 3 4
+ *     export-png 'out.png'
+ *
+ * Messages are printed as they come. The script stops at the first
+ * error, which is returned with the line it was on.
+ */
+pub fn run_script(path: &Path, state: &mut State, windows: &mut [Box<Window>],
+                  commands: &mut Vec<(Vec<Input>, Command)>) -> Result<(), String> {
+    let file = try!(File::open(path).map_err(|error| format!("{}: {}", path.display(), error)));
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|error| format!("{}: {}", path.display(), error)));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = run_line(line, state, windows, commands);
+        print_output(state);
+        match result {
+            Ok(true) => return Ok(()),
+            Ok(false) => {},
+            Err(message) => return Err(format!("{}:{}: {}", path.display(), number + 1, message)),
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Runs a line as `M-x` with its arguments already entered, returning
+ * whether it quit
+ */
+fn run_line(line: &str, state: &mut State, windows: &mut [Box<Window>],
+            commands: &mut Vec<(Vec<Input>, Command)>) -> Result<bool, String> {
+    let invocation = try!(macros::parse_invocation(line, state.current_palette()));
+    state.input = vec![input::META_X, Input::Exact(String::from(invocation.command.name()))];
+    state.input.extend(invocation.args.iter().map(|arg| match *arg {
+        input::Arg::Integer(_) => ArgType::Integer.input(),
+        input::Arg::Color(_) => ArgType::Color.input(),
+        input::Arg::String(_) => ArgType::String.input(),
+    }));
+    state.args = invocation.args;
+    state.prefix_arg = invocation.count.map(::state::PrefixArg::Count);
+
+    match input::execute_command(state, windows, commands) {
+        CommandResult::Quit => Ok(true),
+        CommandResult::Error(message) => {
+            // already shown as a message, which would print it twice
+            state.message = None;
+            Err(message)
+        },
+        CommandResult::RequiresMoreInput => {
+            state.input.clear();
+            state.args.clear();
+            state.prefix_arg = None;
+            Err(format!("{} takes more arguments than that", invocation.command.name()))
+        },
+        CommandResult::NoValidCommand => Err(format!("can't run `{}`", line)),
+        CommandResult::ReloadConfig => Err(String::from("reload-config can't run in a script")),
+        CommandResult::BindingsChanged => {
+            macros::rebind(commands, &state.macros);
+            Ok(false)
+        },
+        CommandResult::Success => Ok(false),
+    }
+}
+
+/*
+ * Messages go to stdout, or stderr if they're warnings or errors, and the
+ * help window's text to stdout
+ */
+fn print_output(state: &mut State) {
+    if let Some(message) = state.message.take() {
+        match message.level {
+            MessageLevel::Info => println!("{}", message.text),
+            MessageLevel::Warning | MessageLevel::Error => {
+                writeln!(io::stderr(), "{}", message.text).ok();
+            },
+        }
+    }
+    if let Some(lines) = state.help_text.take() {
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env,fs};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use image_buffer::ImageBuffer;
+    use input;
+    use state::State;
+    use util::color_to_rgba;
+    use super::run_script;
+
+    fn write_script(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        fs::File::create(&path).and_then(|mut file| file.write_all(text.as_bytes())).unwrap();
+        path
+    }
+
+    fn run(path: &PathBuf) -> (Result<(), String>, State) {
+        let mut windows = ::initialize_windows();
+        let mut state = State{images: vec![ImageBuffer::new(32,32)], ..State::new()};
+        let mut commands = input::get_commands();
+        let result = run_script(path, &mut state, &mut windows, &mut commands);
+        (result, state)
+    }
+
+    #[test]
+    fn draws_and_exports() {
+        let out = env::temp_dir().join("rusted_pixels_script_test.png");
+        let path = write_script("rusted_pixels_script_test.rpcmd", &format!(
+            "# comments and blank lines are skipped\n\
+             \n\
+             new-image 8 4\n\
+             set-color #ff0000\n\
+             fill 3 2\n\
+             set-color rgb 0,0,255\n\
+             2 cursor-right\n\
+             plot\n\
+             export-png '{}'\n", out.display()));
+        let (result, state) = run(&path);
+        assert_eq!(result, Ok(()));
+        assert!(state.input.is_empty() && state.args.is_empty());
+
+        let image = ImageBuffer::load_png_image(&out).unwrap();
+        assert_eq!((image.width, image.height), (8, 4));
+        assert_eq!(color_to_rgba(image.get_point(7, 3)), (255,0,0,255));
+        assert_eq!(color_to_rgba(image.get_point(2, 0)), (0,0,255,255));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn stops_at_the_first_error() {
+        let path = write_script("rusted_pixels_script_error_test.rpcmd",
+                                "set-color #00ff00\n\
+                                 plot 1 1\n\
+                                 \n\
+                                 plot 100 100\n\
+                                 plot 2 2\n");
+        let (result, state) = run(&path);
+        let message = result.unwrap_err();
+        assert!(message.starts_with(&format!("{}:4: ", path.display())), "{}", message);
+        assert!(message.contains("outside"), "{}", message);
+        // what came before ran, what came after didn't
+        assert_eq!(color_to_rgba(state.images[0].get_point(1, 1)), (0,255,0,255));
+        assert_eq!(color_to_rgba(state.images[0].get_point(2, 2)), (0,0,0,0));
+
+        let path = write_script("rusted_pixels_script_error_test.rpcmd",
+                                "zoom-in\nno-such-command\n");
+        let message = run(&path).0.unwrap_err();
+        assert!(message.starts_with(&format!("{}:2: ", path.display())), "{}", message);
+        fs::remove_file(&path).unwrap();
+
+        let missing = env::temp_dir().join("rusted_pixels_no_such_script.rpcmd");
+        assert!(run(&missing).0.is_err());
+    }
+
+    #[test]
+    fn missing_arguments_are_an_error() {
+        let path = write_script("rusted_pixels_script_arguments_test.rpcmd",
+                                "zoom-in\nset-color\n");
+        let (result, state) = run(&path);
+        assert_eq!(result, Err(format!("{}:2: set-color takes more arguments than that",
+                                       path.display())));
+        assert!(state.input.is_empty() && state.args.is_empty());
+        assert_eq!(state.prefix_arg, None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quit_ends_the_script() {
+        let path = write_script("rusted_pixels_script_quit_test.rpcmd",
+                                "quit\nno-such-command\n");
+        assert_eq!(run(&path).0, Ok(()));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use image_buffer::ImageBuffer;
+
+/*
+ * The pixels of a one pixel wide line between two points, both included
+ */
+pub fn line(from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+    let (mut x, mut y) = (from.0 as isize, from.1 as isize);
+    let (to_x, to_y) = (to.0 as isize, to.1 as isize);
+    let dx = (to_x - x).abs();
+    let dy = -(to_y - y).abs();
+    let step_x = if x < to_x { 1 } else { -1 };
+    let step_y = if y < to_y { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    // Bresenham's, which steps along both axes at once on diagonals
+    let mut points = vec![];
+    loop {
+        points.push((x as usize, y as usize));
+        if x == to_x && y == to_y {
+            return points;
+        }
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/*
+ * The outline of the rectangle with corners `a` and `b`, each pixel once
+ */
+pub fn rectangle(a: (usize, usize), b: (usize, usize)) -> Vec<(usize, usize)> {
+    let (left, right) = (a.0.min(b.0), a.0.max(b.0));
+    let (top, bottom) = (a.1.min(b.1), a.1.max(b.1));
+    let mut points = vec![];
+    for x in left..(right + 1) {
+        points.push((x, top));
+        if bottom != top {
+            points.push((x, bottom));
+        }
+    }
+    for y in (top + 1)..bottom {
+        points.push((left, y));
+        if right != left {
+            points.push((right, y));
+        }
+    }
+    points
+}
+
+/*
+ * The pixels connected to `start` that have its color, not counting
+ * diagonals
+ */
+pub fn flood_fill(image: &ImageBuffer, start: (usize, usize)) -> Vec<(usize, usize)> {
+    let target = image.get_point(start.0, start.1);
+    let mut seen = vec![false; image.width * image.height];
+    let mut stack = vec![start];
+    let mut points = vec![];
+    seen[start.1 * image.width + start.0] = true;
+    while let Some((x, y)) = stack.pop() {
+        points.push((x, y));
+        let mut neighbours = vec![];
+        if x > 0 { neighbours.push((x - 1, y)); }
+        if y > 0 { neighbours.push((x, y - 1)); }
+        if x + 1 < image.width { neighbours.push((x + 1, y)); }
+        if y + 1 < image.height { neighbours.push((x, y + 1)); }
+        for (nx, ny) in neighbours {
+            let index = ny * image.width + nx;
+            if !seen[index] && image.get_point(nx, ny) == target {
+                seen[index] = true;
+                stack.push((nx, ny));
+            }
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use image_buffer::ImageBuffer;
+    use super::{line, rectangle, flood_fill};
+
+    fn sorted(mut points: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        points.sort();
+        points
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(line((2, 3), (2, 3)), vec![(2, 3)]);
+        assert_eq!(line((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(line((1, 3), (1, 1)), vec![(1, 3), (1, 2), (1, 1)]);
+        assert_eq!(line((0, 0), (2, 2)), vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(line((3, 0), (0, 3)), vec![(3, 0), (2, 1), (1, 2), (0, 3)]);
+        assert_eq!(line((0, 0), (4, 2)), vec![(0, 0), (1, 1), (2, 1), (3, 2), (4, 2)]);
+        // one pixel per step along the longer axis
+        assert_eq!(line((0, 0), (7, 3)).len(), 8);
+        assert_eq!(line((5, 1), (0, 3)).len(), 6);
+    }
+
+    #[test]
+    fn rectangles() {
+        assert_eq!(rectangle((2, 2), (2, 2)), vec![(2, 2)]);
+        assert_eq!(sorted(rectangle((0, 1), (3, 1))), vec![(0, 1), (1, 1), (2, 1), (3, 1)]);
+        assert_eq!(sorted(rectangle((1, 3), (1, 0))), vec![(1, 0), (1, 1), (1, 2), (1, 3)]);
+        assert_eq!(sorted(rectangle((2, 2), (0, 0))),
+                   vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 2), (2, 0), (2, 1), (2, 2)]);
+        assert_eq!(sorted(rectangle((0, 0), (1, 1))), vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+        // every pixel only once, so blending doesn't go over corners twice
+        let outline = rectangle((1, 1), (5, 4));
+        let mut unique = sorted(outline.clone());
+        unique.dedup();
+        assert_eq!(unique.len(), outline.len());
+        assert_eq!(outline.len(), 2 * 5 + 2 * 2);
+    }
+
+    /*
+     * A 4x3 image with a wall at x = 2:
+     *
+     *     . . # .
+     *     . . # .
+     *     . . . .
+     */
+    fn walled() -> ImageBuffer {
+        let mut image = ImageBuffer::new(4, 3);
+        image.set_point(2, 0, Color::RGB(0,0,0));
+        image.set_point(2, 1, Color::RGB(0,0,0));
+        image
+    }
+
+    #[test]
+    fn fills() {
+        let image = walled();
+        assert_eq!(sorted(flood_fill(&image, (0, 0))),
+                   vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (2, 2),
+                        (3, 0), (3, 1), (3, 2)]);
+        assert_eq!(sorted(flood_fill(&image, (2, 1))), vec![(2, 0), (2, 1)]);
+
+        // diagonals don't connect
+        let mut image = ImageBuffer::new(2, 2);
+        image.set_point(1, 0, Color::RGB(0,0,0));
+        image.set_point(0, 1, Color::RGB(0,0,0));
+        assert_eq!(flood_fill(&image, (0, 0)), vec![(0, 0)]);
+
+        let single = ImageBuffer::new(1, 1);
+        assert_eq!(flood_fill(&single, (0, 0)), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn filling_with_the_same_color_finds_the_region_once() {
+        // the fill doesn't know the color it's painting with, so it has to
+        // stop on its own when the region already has it
+        let image = ImageBuffer::new(5, 5);
+        let points = flood_fill(&image, (2, 2));
+        let mut unique = sorted(points.clone());
+        unique.dedup();
+        assert_eq!(points.len(), 25);
+        assert_eq!(unique.len(), 25);
+    }
+}
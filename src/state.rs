@@ -1,7 +1,11 @@
 
 use sdl2::pixels::Color;
+use time;
 use sdl2::keyboard::{Keycode,Mod,LALTMOD,LCTRLMOD};
 use image_buffer::ImageBuffer;
+use palette::Palette;
+use history::History;
+use macros::{Invocation, Macro};
 use input::{Input, Arg, keycode_to_char};
 
 /*
@@ -9,18 +13,186 @@ use input::{Input, Arg, keycode_to_char};
  */
 pub struct State {
     pub current_color: Color,
+    pub paint_mode: PaintMode,
+    pub grid: Grid,
     pub left_mouse_down: bool,
     pub right_mouse_down: bool,
+    // The window being dragged around with the middle mouse button
+    pub panning_window: Option<usize>,
     pub mouse_x: i32,
     pub mouse_y: i32,
+    // Image and pixel under the mouse, as `(image_id, x, y)`
+    pub hover: Option<(usize, usize, usize)>,
+    // Keyboard drawing happens at the cursor, lines and rectangles go
+    // from the mark to it
+    pub canvas_cursor: Option<(usize, usize)>,
+    pub mark: Option<(usize, usize)>,
+    // Scale and image of the active window, if it has them
+    pub zoom: Option<usize>,
+    pub active_image_id: Option<usize>,
     pub images: Vec<ImageBuffer>,
+    pub layers: Vec<Layer>,
     pub current_palette_index: usize,
-    pub palettes: Vec<Vec<Color>>,//TODO: Multiple palettes
+    pub palettes: Vec<Palette>,
     pub input: Vec<Input>,
     pub args: Vec<Arg>,
+    // Count for the next command, entered before it
+    pub prefix_arg: Option<PrefixArg>,
     pub input_buffer: String,
+    // Byte index of the cursor in `input_buffer`
+    pub input_cursor: usize,
+    // Set when the last key down was a command, so its text is ignored
+    pub suppress_text_input: bool,
+    pub history: History,
+    // Candidates from the last Tab, shown above the status bar
+    pub completions: Vec<String>,
+    // Set by `C-h k` and describe-key-briefly while reading the keys to
+    // describe, `true` for the brief description
+    pub describing_key: Option<bool>,
+    // Lines shown in the help window, while it's open
+    pub help_text: Option<Vec<String>>,
+    // First line of `help_text` shown, and how many fit in the window
+    pub help_scroll: usize,
+    pub help_page: usize,
     pub window_index: usize,
     pub undo_stack: Vec<Undo>,
+    // Commands run since `C-x (`, until `C-x )` makes them the last macro
+    pub recording: Option<Vec<Invocation>>,
+    pub last_macro: Vec<Invocation>,
+    pub macros: Vec<Macro>,
+    pub message: Option<Message>,
+}
+
+/*
+ * How painting with `current_color` combines with the pixel below
+ */
+#[derive(Copy, Clone, PartialEq)]
+pub enum PaintMode {
+    // Composite the color over the pixel, respecting its alpha
+    Blend,
+    // Overwrite the pixel, alpha included
+    Replace,
+}
+
+/*
+ * A count given before a command, as in `C-u f` or `4 f`. Commands that
+ * move or step use it as how far, most others ignore it.
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PrefixArg {
+    // `C-u` pressed this many times, each multiplying the count by 4
+    Universal(u32),
+    // Typed digits
+    Count(isize),
+}
+
+// Counts are capped at this, which is more than any command needs and
+// keeps distances computed from them from overflowing
+pub const MAX_PREFIX_COUNT: isize = 1000000;
+
+impl PrefixArg {
+    pub fn count(&self) -> isize {
+        match *self {
+            PrefixArg::Universal(times) => (0..times).fold(1isize, |count, _| {
+                count.checked_mul(4).unwrap_or(MAX_PREFIX_COUNT).min(MAX_PREFIX_COUNT)
+            }),
+            PrefixArg::Count(count) => clamp_count(count),
+        }
+    }
+}
+
+/*
+ * Limits a count to `MAX_PREFIX_COUNT` either way
+ */
+pub fn clamp_count(count: isize) -> isize {
+    count.max(-MAX_PREFIX_COUNT).min(MAX_PREFIX_COUNT)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum MessageLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+/*
+ * A message shown in the status window until `expires`
+ */
+pub struct Message {
+    pub level: MessageLevel,
+    pub text: String,
+    pub expires: time::Timespec,
+}
+
+impl Message {
+    pub fn new(level: MessageLevel, text: String) -> Self {
+        // errors stay up longer since they're more important to read
+        let seconds = match level {
+            MessageLevel::Info => 3,
+            MessageLevel::Warning => 5,
+            MessageLevel::Error => 8,
+        };
+        Message {
+            level: level,
+            text: text,
+            expires: time::get_time() + time::Duration::seconds(seconds),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        time::get_time() >= self.expires
+    }
+}
+
+/*
+ * Grid overlays drawn over the images when zoomed in
+ */
+pub struct Grid {
+    pub show_pixel_grid: bool,
+    pub pixel_grid_color: Color,
+    pub show_tile_grid: bool,
+    pub tile_grid_color: Color,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub tile_offset_x: usize,
+    pub tile_offset_y: usize,
+}
+
+impl Grid {
+    pub fn new() -> Self {
+        Grid {
+            show_pixel_grid: false,
+            pixel_grid_color: Color::RGBA(0,0,0,48),
+            show_tile_grid: false,
+            tile_grid_color: Color::RGBA(80,160,255,160),
+            tile_width: 8,
+            tile_height: 8,
+            tile_offset_x: 0,
+            tile_offset_y: 0,
+        }
+    }
+}
+
+/*
+ * Metadata of a layer, stacked bottom to top. The pixels live in
+ * `State::images[image_id]`.
+ */
+pub struct Layer {
+    pub name: String,
+    pub opacity: f32,
+    pub visible: bool,
+    pub image_id: usize,
+}
+
+impl Layer {
+    pub fn new(name: String, image_id: usize) -> Self {
+        Layer {
+            name: name,
+            opacity: 1.0,
+            visible: true,
+            image_id: image_id,
+        }
+    }
 }
 
 /*
@@ -64,29 +236,99 @@ impl State {
     pub fn new() -> Self {
         State {
             current_color: Color::RGB(255,255,255),
+            paint_mode: PaintMode::Blend,
+            grid: Grid::new(),
             left_mouse_down: false,
             right_mouse_down: false,
+            panning_window: None,
             mouse_x: 0,
             mouse_y: 0,
+            hover: None,
+            canvas_cursor: None,
+            mark: None,
+            zoom: None,
+            active_image_id: None,
             images: vec![],
+            layers: vec![],
             current_palette_index: 0,
-            palettes: vec![vec![
+            palettes: vec![Palette::new(String::from("default"), vec![
                 Color::RGB(0,0,0),
                 Color::RGB(128,128,128),
                 Color::RGB(255,255,255),
                 Color::RGB(192,128,112),
-            ]],
+            ])],
             input: Vec::new(),
             args: Vec::new(),
+            prefix_arg: None,
             input_buffer: String::new(),
+            input_cursor: 0,
+            suppress_text_input: false,
+            history: History::new(),
+            completions: vec![],
+            describing_key: None,
+            help_text: None,
+            help_scroll: 0,
+            help_page: 1,
             window_index: 0,
             undo_stack: vec![],
+            recording: None,
+            last_macro: vec![],
+            macros: vec![],
+            message: None,
         }
     }
 
     #[inline(always)]
     pub fn current_palette<'a>(&'a self) -> &'a [Color] {
-        &self.palettes[self.current_palette_index]
+        &self.palettes[self.current_palette_index].colors
+    }
+
+    #[inline(always)]
+    pub fn current_palette_mut<'a>(&'a mut self) -> &'a mut Palette {
+        &mut self.palettes[self.current_palette_index]
+    }
+
+    /*
+     * Adds a palette and makes it the current one
+     */
+    pub fn push_palette(&mut self, palette: Palette) {
+        self.palettes.push(palette);
+        self.current_palette_index = self.palettes.len() - 1;
+    }
+
+    /*
+     * Opens the help window on `lines`, from the top
+     */
+    pub fn show_help(&mut self, lines: Vec<String>) {
+        self.help_text = Some(lines);
+        self.help_scroll = 0;
+    }
+
+    /*
+     * Scrolls the help window down by `lines`, or up if negative, from
+     * the first line to the page ending with the last
+     */
+    pub fn scroll_help(&mut self, lines: isize) {
+        let length = self.help_text.as_ref().map_or(0, |text| text.len());
+        let last = length.saturating_sub(self.help_page.max(1));
+        let scroll = (self.help_scroll as isize).saturating_add(lines);
+        self.help_scroll = if scroll < 0 { 0 } else { (scroll as usize).min(last) };
+    }
+
+    pub fn show_message(&mut self, level: MessageLevel, text: String) {
+        self.message = Some(Message::new(level, text));
+    }
+
+    pub fn info<S: Into<String>>(&mut self, text: S) {
+        self.show_message(MessageLevel::Info, text.into());
+    }
+
+    pub fn warning<S: Into<String>>(&mut self, text: S) {
+        self.show_message(MessageLevel::Warning, text.into());
+    }
+
+    pub fn error<S: Into<String>>(&mut self, text: S) {
+        self.show_message(MessageLevel::Error, text.into());
     }
 
     pub fn undo(&mut self) {
@@ -107,7 +349,11 @@ impl State {
             }
         }
         
-        let mut string = String::new();
+        let mut string = match self.prefix_arg {
+            Some(PrefixArg::Universal(times)) => (0..times).map(|_| "C-u ").collect(),
+            Some(PrefixArg::Count(count)) => count.to_string() + " ",
+            None => String::new(),
+        };
         let mut count = 0;
         for input in &self.input {
             match *input {
@@ -116,23 +362,27 @@ impl State {
                     string.push(keycode_to_char(keycode).unwrap_or(' '));
                 },
                 Input::Integer => {
-                    string = string + &self.args[count].coerce_integer().to_string();
+                    if let Some(integer) = self.args.get(count).and_then(Arg::as_integer) {
+                        string = string + &integer.to_string();
+                    }
                     count += 1;
                 },
                 Input::Exact(ref exact_phrase) => {
                     string = string + exact_phrase;
                 },
                 Input::String => {
-                    string = string + &self.args[count].coerce_string();
+                    if let Some(text) = self.args.get(count).and_then(Arg::as_string) {
+                        string = string + text;
+                    }
                     count += 1;
                 },
                 Input::Color => {
-                    let color = &self.args[count].coerce_color();
-                    if let Color::RGB(r,g,b) = *color {
+                    let color = self.args.get(count).and_then(Arg::as_color);
+                    if let Some(Color::RGB(r,g,b)) = color {
                         string = string + "rgb(" + &r.to_string() + ","
                             + &g.to_string() + ","
                             + &b.to_string() + ")"
-                    } else if let Color::RGBA(r,g,b,a) = *color {
+                    } else if let Some(Color::RGBA(r,g,b,a)) = color {
                         string = string + "rgb(" + &r.to_string()
                             + "," + &g.to_string() + ","
                             + &b.to_string() + "," + &a.to_string() + ")"
@@ -142,7 +392,140 @@ impl State {
             }
             string = string + " ";
         }
-        string = string + " " + &self.input_buffer;
+        string = string + " " + &self.input_buffer[..self.input_cursor];
+        if !self.input.is_empty() {
+            string.push('|');
+        }
+        string = string + &self.input_buffer[self.input_cursor..];
         return string;
     }
+
+    /*
+     * Adds a digit to the count, where `C-u 1 2` and `1 2` both give 12
+     */
+    pub fn push_prefix_digit(&mut self, digit: isize) {
+        self.prefix_arg = Some(match self.prefix_arg {
+            Some(PrefixArg::Count(count)) => PrefixArg::Count(
+                clamp_count(count.saturating_mul(10).saturating_add(digit))),
+            _ => PrefixArg::Count(digit),
+        });
+    }
+
+    /*
+     * Replaces the minibuffer contents, with the cursor at the end
+     */
+    pub fn set_input_buffer(&mut self, text: String) {
+        self.input_cursor = text.len();
+        self.input_buffer = text;
+    }
+
+    pub fn insert_text(&mut self, text: &str) {
+        self.input_buffer.insert_str(self.input_cursor, text);
+        self.input_cursor += text.len();
+    }
+
+    fn char_before_cursor(&self) -> Option<char> {
+        self.input_buffer[..self.input_cursor].chars().next_back()
+    }
+
+    pub fn delete_backward(&mut self) {
+        if let Some(c) = self.char_before_cursor() {
+            self.input_cursor -= c.len_utf8();
+            self.input_buffer.remove(self.input_cursor);
+        }
+    }
+
+    pub fn cursor_left(&mut self) {
+        if let Some(c) = self.char_before_cursor() {
+            self.input_cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn cursor_right(&mut self) {
+        if let Some(c) = self.input_buffer[self.input_cursor..].chars().next() {
+            self.input_cursor += c.len_utf8();
+        }
+    }
+
+    // C-k
+    pub fn kill_to_end(&mut self) {
+        self.input_buffer.truncate(self.input_cursor);
+    }
+
+    // C-w, deleting back to the previous space like a shell does
+    pub fn kill_word_backward(&mut self) {
+        let before = self.input_buffer[..self.input_cursor].trim_right().len();
+        let start = self.input_buffer[..before].rfind(' ').map(|index| index + 1).unwrap_or(0);
+        self.input_buffer.drain(start..self.input_cursor);
+        self.input_cursor = start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{State, PrefixArg, MAX_PREFIX_COUNT};
+
+    #[test]
+    fn universal_counts() {
+        assert_eq!(PrefixArg::Universal(0).count(), 1);
+        assert_eq!(PrefixArg::Universal(1).count(), 4);
+        assert_eq!(PrefixArg::Universal(3).count(), 64);
+        assert_eq!(PrefixArg::Universal(40).count(), MAX_PREFIX_COUNT);
+        assert_eq!(PrefixArg::Universal(u32::max_value()).count(), MAX_PREFIX_COUNT);
+    }
+
+    #[test]
+    fn typed_counts() {
+        assert_eq!(PrefixArg::Count(12).count(), 12);
+        assert_eq!(PrefixArg::Count(-3).count(), -3);
+        assert_eq!(PrefixArg::Count(isize::max_value()).count(), MAX_PREFIX_COUNT);
+        assert_eq!(PrefixArg::Count(isize::min_value()).count(), -MAX_PREFIX_COUNT);
+    }
+
+    #[test]
+    fn digits_accumulate() {
+        let mut state = State::new();
+        state.push_prefix_digit(4);
+        assert_eq!(state.prefix_arg, Some(PrefixArg::Count(4)));
+        state.push_prefix_digit(0);
+        state.push_prefix_digit(2);
+        assert_eq!(state.prefix_arg, Some(PrefixArg::Count(402)));
+
+        // a digit after C-u starts a new count
+        state.prefix_arg = Some(PrefixArg::Universal(2));
+        state.push_prefix_digit(7);
+        assert_eq!(state.prefix_arg, Some(PrefixArg::Count(7)));
+
+        for _ in 0..30 {
+            state.push_prefix_digit(9);
+        }
+        assert_eq!(state.prefix_arg, Some(PrefixArg::Count(MAX_PREFIX_COUNT)));
+    }
+
+    #[test]
+    fn help_scrolls_within_its_text() {
+        let mut state = State::new();
+        state.help_page = 10;
+        state.show_help((0..25).map(|line| line.to_string()).collect());
+        state.scroll_help(-1);
+        assert_eq!(state.help_scroll, 0);
+        state.scroll_help(10);
+        assert_eq!(state.help_scroll, 10);
+        // the last page ends with the last line
+        state.scroll_help(10);
+        assert_eq!(state.help_scroll, 15);
+        state.scroll_help(isize::max_value());
+        assert_eq!(state.help_scroll, 15);
+        state.scroll_help(-4);
+        assert_eq!(state.help_scroll, 11);
+        state.scroll_help(isize::min_value() + 1);
+        assert_eq!(state.help_scroll, 0);
+
+        // new help starts at the top, and one that fits doesn't scroll
+        state.scroll_help(3);
+        state.show_help(vec![String::from("short")]);
+        assert_eq!(state.help_scroll, 0);
+        state.scroll_help(10);
+        assert_eq!(state.help_scroll, 0);
+    }
 }
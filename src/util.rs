@@ -1,18 +1,279 @@
 use sdl2::pixels::Color;
 
-pub fn parse_color(string: &str) -> Option<Color> {
+use color_space;
+
+/*
+ * Parses any of
+ *  * `rgb r,g,b`, `rgba r,g,b,a`, `rgb(r,g,b)` and `rgba(r,g,b,a)`
+ *  * `#rgb`, `#rgba`, `#rrggbb` and `#rrggbbaa`
+ *  * `hsv(h,s,v)` and `hsl(h,s,l)`, hue in degrees and the rest in percent
+ *  * CSS color names, like `rebeccapurple`
+ *  * `pN`, the N:th color of `palette`
+ */
+pub fn parse_color(string: &str, palette: &[Color]) -> Option<Color> {
+    let string = string.trim();
     if string.starts_with("rgb ") {
         parse_rgb(&string[4..])
     } else if string.starts_with("rgba ") {
         parse_rgba(&string[5..])
+    } else if let Some(inner) = parenthesized(string, "rgb") {
+        parse_rgb(inner)
+    } else if let Some(inner) = parenthesized(string, "rgba") {
+        parse_rgba(inner)
+    } else if let Some(inner) = parenthesized(string, "hsv") {
+        parse_hsv(inner)
+    } else if let Some(inner) = parenthesized(string, "hsl") {
+        parse_hsl(inner)
     } else if string.starts_with('#') {
-        //TODO: hexadecimal conversion
-        None
+        parse_hex(&string[1..])
+    } else if string.starts_with('p') && string.len() > 1
+        && string[1..].chars().all(|c| c.is_digit(10))
+    {
+        string[1..].parse::<usize>().ok().and_then(|index| palette.get(index).cloned())
     } else {
-        None
+        parse_named(string)
+    }
+}
+
+/*
+ * Returns what's between the parentheses of `function(...)`
+ */
+fn parenthesized<'a>(string: &'a str, function: &str) -> Option<&'a str> {
+    if string.starts_with(function) && string.ends_with(')') {
+        let rest = string[function.len()..].trim_left();
+        if rest.starts_with('(') {
+            return Some(&rest[1..rest.len() - 1]);
+        }
     }
+    None
+}
+
+/*
+ * Hexadecimal colors without the leading `#`, one or two digits per
+ * component
+ */
+pub fn parse_hex(string: &str) -> Option<Color> {
+    if !string.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let digits = match string.len() {
+        3 | 4 => 1,
+        6 | 8 => 2,
+        _ => return None,
+    };
+    let components = (0..string.len() / digits)
+        .map(|i| {
+            let component = u8::from_str_radix(&string[i * digits..(i + 1) * digits], 16).unwrap();
+            // `f` is short for `ff`
+            if digits == 1 { component * 17 } else { component }
+        })
+        .collect::<Vec<u8>>();
+    match components.len() {
+        3 => Some(Color::RGB(components[0], components[1], components[2])),
+        _ => Some(Color::RGBA(components[0], components[1], components[2], components[3])),
+    }
+}
+
+pub fn parse_hsv(string: &str) -> Option<Color> {
+    parse_degrees_and_percentages(string)
+        .map(|(hue, saturation, value)| color_space::from_hsv(hue, saturation, value, 255))
+}
+
+pub fn parse_hsl(string: &str) -> Option<Color> {
+    parse_degrees_and_percentages(string)
+        .map(|(hue, saturation, lightness)| color_space::from_hsl(hue, saturation, lightness, 255))
+}
+
+/*
+ * `h,s,x` where `h` is in degrees and the others in percent, with or
+ * without `%`. Percentages are returned as 0..1.
+ */
+fn parse_degrees_and_percentages(string: &str) -> Option<(f32, f32, f32)> {
+    let filtered = string
+        .chars()
+        .filter(|c| *c != ' ' && *c != '%')
+        .collect::<String>();
+    let splitted = filtered
+        .split(',')
+        .collect::<Vec<&str>>();
+
+    if splitted.len() == 3 {
+        if let (Ok(hue), Ok(a), Ok(b)) = (splitted[0].parse::<f32>(),
+                                          splitted[1].parse::<f32>(),
+                                          splitted[2].parse::<f32>())
+        {
+            if 0.0 <= a && a <= 100.0 && 0.0 <= b && b <= 100.0 {
+                return Some((hue, a / 100.0, b / 100.0));
+            }
+        }
+    }
+    None
 }
 
+pub fn parse_named(string: &str) -> Option<Color> {
+    let name = string.to_lowercase();
+    if name == "transparent" {
+        return Some(Color::RGBA(0,0,0,0));
+    }
+    NAMED_COLORS.iter()
+        .find(|&&(color_name, _)| color_name == name)
+        .map(|&(_, (r,g,b))| Color::RGB(r,g,b))
+}
+
+/*
+ * The CSS named colors
+ */
+const NAMED_COLORS: &'static [(&'static str, (u8, u8, u8))] = &[
+    ("aliceblue", (240, 248, 255)),
+    ("antiquewhite", (250, 235, 215)),
+    ("aqua", (0, 255, 255)),
+    ("aquamarine", (127, 255, 212)),
+    ("azure", (240, 255, 255)),
+    ("beige", (245, 245, 220)),
+    ("bisque", (255, 228, 196)),
+    ("black", (0, 0, 0)),
+    ("blanchedalmond", (255, 235, 205)),
+    ("blue", (0, 0, 255)),
+    ("blueviolet", (138, 43, 226)),
+    ("brown", (165, 42, 42)),
+    ("burlywood", (222, 184, 135)),
+    ("cadetblue", (95, 158, 160)),
+    ("chartreuse", (127, 255, 0)),
+    ("chocolate", (210, 105, 30)),
+    ("coral", (255, 127, 80)),
+    ("cornflowerblue", (100, 149, 237)),
+    ("cornsilk", (255, 248, 220)),
+    ("crimson", (220, 20, 60)),
+    ("cyan", (0, 255, 255)),
+    ("darkblue", (0, 0, 139)),
+    ("darkcyan", (0, 139, 139)),
+    ("darkgoldenrod", (184, 134, 11)),
+    ("darkgray", (169, 169, 169)),
+    ("darkgreen", (0, 100, 0)),
+    ("darkgrey", (169, 169, 169)),
+    ("darkkhaki", (189, 183, 107)),
+    ("darkmagenta", (139, 0, 139)),
+    ("darkolivegreen", (85, 107, 47)),
+    ("darkorange", (255, 140, 0)),
+    ("darkorchid", (153, 50, 204)),
+    ("darkred", (139, 0, 0)),
+    ("darksalmon", (233, 150, 122)),
+    ("darkseagreen", (143, 188, 143)),
+    ("darkslateblue", (72, 61, 139)),
+    ("darkslategray", (47, 79, 79)),
+    ("darkslategrey", (47, 79, 79)),
+    ("darkturquoise", (0, 206, 209)),
+    ("darkviolet", (148, 0, 211)),
+    ("deeppink", (255, 20, 147)),
+    ("deepskyblue", (0, 191, 255)),
+    ("dimgray", (105, 105, 105)),
+    ("dimgrey", (105, 105, 105)),
+    ("dodgerblue", (30, 144, 255)),
+    ("firebrick", (178, 34, 34)),
+    ("floralwhite", (255, 250, 240)),
+    ("forestgreen", (34, 139, 34)),
+    ("fuchsia", (255, 0, 255)),
+    ("gainsboro", (220, 220, 220)),
+    ("ghostwhite", (248, 248, 255)),
+    ("gold", (255, 215, 0)),
+    ("goldenrod", (218, 165, 32)),
+    ("gray", (128, 128, 128)),
+    ("green", (0, 128, 0)),
+    ("greenyellow", (173, 255, 47)),
+    ("grey", (128, 128, 128)),
+    ("honeydew", (240, 255, 240)),
+    ("hotpink", (255, 105, 180)),
+    ("indianred", (205, 92, 92)),
+    ("indigo", (75, 0, 130)),
+    ("ivory", (255, 255, 240)),
+    ("khaki", (240, 230, 140)),
+    ("lavender", (230, 230, 250)),
+    ("lavenderblush", (255, 240, 245)),
+    ("lawngreen", (124, 252, 0)),
+    ("lemonchiffon", (255, 250, 205)),
+    ("lightblue", (173, 216, 230)),
+    ("lightcoral", (240, 128, 128)),
+    ("lightcyan", (224, 255, 255)),
+    ("lightgoldenrodyellow", (250, 250, 210)),
+    ("lightgray", (211, 211, 211)),
+    ("lightgreen", (144, 238, 144)),
+    ("lightgrey", (211, 211, 211)),
+    ("lightpink", (255, 182, 193)),
+    ("lightsalmon", (255, 160, 122)),
+    ("lightseagreen", (32, 178, 170)),
+    ("lightskyblue", (135, 206, 250)),
+    ("lightslategray", (119, 136, 153)),
+    ("lightslategrey", (119, 136, 153)),
+    ("lightsteelblue", (176, 196, 222)),
+    ("lightyellow", (255, 255, 224)),
+    ("lime", (0, 255, 0)),
+    ("limegreen", (50, 205, 50)),
+    ("linen", (250, 240, 230)),
+    ("magenta", (255, 0, 255)),
+    ("maroon", (128, 0, 0)),
+    ("mediumaquamarine", (102, 205, 170)),
+    ("mediumblue", (0, 0, 205)),
+    ("mediumorchid", (186, 85, 211)),
+    ("mediumpurple", (147, 112, 219)),
+    ("mediumseagreen", (60, 179, 113)),
+    ("mediumslateblue", (123, 104, 238)),
+    ("mediumspringgreen", (0, 250, 154)),
+    ("mediumturquoise", (72, 209, 204)),
+    ("mediumvioletred", (199, 21, 133)),
+    ("midnightblue", (25, 25, 112)),
+    ("mintcream", (245, 255, 250)),
+    ("mistyrose", (255, 228, 225)),
+    ("moccasin", (255, 228, 181)),
+    ("navajowhite", (255, 222, 173)),
+    ("navy", (0, 0, 128)),
+    ("oldlace", (253, 245, 230)),
+    ("olive", (128, 128, 0)),
+    ("olivedrab", (107, 142, 35)),
+    ("orange", (255, 165, 0)),
+    ("orangered", (255, 69, 0)),
+    ("orchid", (218, 112, 214)),
+    ("palegoldenrod", (238, 232, 170)),
+    ("palegreen", (152, 251, 152)),
+    ("paleturquoise", (175, 238, 238)),
+    ("palevioletred", (219, 112, 147)),
+    ("papayawhip", (255, 239, 213)),
+    ("peachpuff", (255, 218, 185)),
+    ("peru", (205, 133, 63)),
+    ("pink", (255, 192, 203)),
+    ("plum", (221, 160, 221)),
+    ("powderblue", (176, 224, 230)),
+    ("purple", (128, 0, 128)),
+    ("rebeccapurple", (102, 51, 153)),
+    ("red", (255, 0, 0)),
+    ("rosybrown", (188, 143, 143)),
+    ("royalblue", (65, 105, 225)),
+    ("saddlebrown", (139, 69, 19)),
+    ("salmon", (250, 128, 114)),
+    ("sandybrown", (244, 164, 96)),
+    ("seagreen", (46, 139, 87)),
+    ("seashell", (255, 245, 238)),
+    ("sienna", (160, 82, 45)),
+    ("silver", (192, 192, 192)),
+    ("skyblue", (135, 206, 235)),
+    ("slateblue", (106, 90, 205)),
+    ("slategray", (112, 128, 144)),
+    ("slategrey", (112, 128, 144)),
+    ("snow", (255, 250, 250)),
+    ("springgreen", (0, 255, 127)),
+    ("steelblue", (70, 130, 180)),
+    ("tan", (210, 180, 140)),
+    ("teal", (0, 128, 128)),
+    ("thistle", (216, 191, 216)),
+    ("tomato", (255, 99, 71)),
+    ("turquoise", (64, 224, 208)),
+    ("violet", (238, 130, 238)),
+    ("wheat", (245, 222, 179)),
+    ("white", (255, 255, 255)),
+    ("whitesmoke", (245, 245, 245)),
+    ("yellow", (255, 255, 0)),
+    ("yellowgreen", (154, 205, 50)),
+];
+
 pub fn parse_rgb(string: &str) -> Option<Color> {
     let filtered = string
         .chars()
@@ -50,3 +311,94 @@ pub fn parse_rgba(string: &str) -> Option<Color> {
     }
     None
 }
+
+/*
+ * Splits any color into its four components, treating `RGB` as opaque.
+ */
+pub fn color_to_rgba(color: Color) -> (u8, u8, u8, u8) {
+    match color {
+        Color::RGB(r,g,b) => (r,g,b,255),
+        Color::RGBA(r,g,b,a) => (r,g,b,a),
+    }
+}
+
+/*
+ * Composites `src` over `dst` (source-over), with `opacity` in 0..1
+ * scaling the alpha of `src`.
+ */
+pub fn blend_over(dst: Color, src: Color, opacity: f32) -> Color {
+    let (dr,dg,db,da) = color_to_rgba(dst);
+    let (sr,sg,sb,sa) = color_to_rgba(src);
+    let sa = sa as f32 / 255.0 * opacity;
+    let da = da as f32 / 255.0;
+    let out_a = sa + da * (1.0 - sa);
+    if out_a <= 0.0 {
+        return Color::RGBA(0,0,0,0);
+    }
+    let channel = |s: u8, d: u8| -> u8 {
+        ((s as f32 * sa + d as f32 * da * (1.0 - sa)) / out_a).round() as u8
+    };
+    Color::RGBA(channel(sr,dr), channel(sg,dg), channel(sb,db),
+                (out_a * 255.0).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+    use super::parse_color;
+
+    #[test]
+    fn rgb() {
+        assert_eq!(parse_color("rgb 1,2,3", &[]), Some(Color::RGB(1,2,3)));
+        assert_eq!(parse_color("rgb(1, 2, 3)", &[]), Some(Color::RGB(1,2,3)));
+        assert_eq!(parse_color("rgba 1,2,3,4", &[]), Some(Color::RGBA(1,2,3,4)));
+        assert_eq!(parse_color("rgba(1,2,3,4)", &[]), Some(Color::RGBA(1,2,3,4)));
+        assert_eq!(parse_color("rgb 1,2,256", &[]), None);
+        assert_eq!(parse_color("rgb 1,2", &[]), None);
+    }
+
+    #[test]
+    fn hexadecimal() {
+        assert_eq!(parse_color("#f80", &[]), Some(Color::RGB(255,136,0)));
+        assert_eq!(parse_color("#f808", &[]), Some(Color::RGBA(255,136,0,136)));
+        assert_eq!(parse_color("#ff8000", &[]), Some(Color::RGB(255,128,0)));
+        assert_eq!(parse_color("#FF800080", &[]), Some(Color::RGBA(255,128,0,128)));
+        assert_eq!(parse_color("#ff80", &[]), Some(Color::RGBA(255,255,136,0)));
+        assert_eq!(parse_color("#ff800", &[]), None);
+        assert_eq!(parse_color("#gg0000", &[]), None);
+        assert_eq!(parse_color("#", &[]), None);
+    }
+
+    #[test]
+    fn hsv() {
+        assert_eq!(parse_color("hsv(0,100,100)", &[]), Some(Color::RGB(255,0,0)));
+        assert_eq!(parse_color("hsv(120, 100%, 50%)", &[]), Some(Color::RGB(0,128,0)));
+        assert_eq!(parse_color("hsv(240,0,0)", &[]), Some(Color::RGB(0,0,0)));
+        assert_eq!(parse_color("hsv(0,101,100)", &[]), None);
+    }
+
+    #[test]
+    fn hsl() {
+        assert_eq!(parse_color("hsl(0,100,50)", &[]), Some(Color::RGB(255,0,0)));
+        assert_eq!(parse_color("hsl(240, 100%, 25%)", &[]), Some(Color::RGB(0,0,128)));
+        assert_eq!(parse_color("hsl(0,0,100)", &[]), Some(Color::RGB(255,255,255)));
+        assert_eq!(parse_color("hsl(0,0)", &[]), None);
+    }
+
+    #[test]
+    fn named() {
+        assert_eq!(parse_color("red", &[]), Some(Color::RGB(255,0,0)));
+        assert_eq!(parse_color("RebeccaPurple", &[]), Some(Color::RGB(102,51,153)));
+        assert_eq!(parse_color("transparent", &[]), Some(Color::RGBA(0,0,0,0)));
+        assert_eq!(parse_color("notacolor", &[]), None);
+    }
+
+    #[test]
+    fn palette_slot() {
+        let palette = [Color::RGB(1,1,1), Color::RGB(2,2,2)];
+        assert_eq!(parse_color("p0", &palette), Some(Color::RGB(1,1,1)));
+        assert_eq!(parse_color("p1", &palette), Some(Color::RGB(2,2,2)));
+        assert_eq!(parse_color("p2", &palette), None);
+        assert_eq!(parse_color("p", &palette), None);
+    }
+}
@@ -0,0 +1,283 @@
+use sdl2::render::Renderer;
+use sdl2::pixels::Color;
+use sdl2::rect::*;
+use sdl2_ttf::Font;
+use std::cell::Cell;
+
+use color_space;
+use state::State;
+use util;
+use windows::Window;
+
+/*
+ * Lets the user pick `state.current_color` with the mouse: a
+ * saturation/value square, a hue strip, one slider per RGB, HSV and alpha
+ * component, a hex readout and a button pushing the color into the
+ * current palette.
+ */
+pub struct ColorPickerWindow {
+    pub x: usize,
+    pub y: usize,
+    // Remembered separately since grays and black have no hue
+    hue: Cell<f32>,
+}
+
+const PICKER_PADDING_PX: usize = 4;
+const PICKER_SQUARE_PX: usize = 128;
+const PICKER_SQUARE_STEP_PX: usize = 4;
+const PICKER_HUE_STRIP_PX: usize = 16;
+const PICKER_SLIDER_HEIGHT_PX: usize = 10;
+const PICKER_SLIDER_STEPS: usize = 32;
+const PICKER_TEXT_HEIGHT_PX: usize = 16;
+
+#[derive(Copy, Clone)]
+enum Slider {
+    Red,
+    Green,
+    Blue,
+    Hue,
+    Saturation,
+    Value,
+    Alpha,
+}
+
+const SLIDERS: [Slider; 7] = [Slider::Red, Slider::Green, Slider::Blue,
+                              Slider::Hue, Slider::Saturation, Slider::Value,
+                              Slider::Alpha];
+
+impl ColorPickerWindow {
+    pub fn new(x: usize, y: usize) -> ColorPickerWindow {
+        ColorPickerWindow {
+            x: x,
+            y: y,
+            hue: Cell::new(0.0),
+        }
+    }
+
+    fn square_rect(&self) -> Rect {
+        Rect::new((self.x + PICKER_PADDING_PX) as i32,
+                  (self.y + PICKER_PADDING_PX) as i32,
+                  PICKER_SQUARE_PX as u32,
+                  PICKER_SQUARE_PX as u32)
+    }
+
+    fn hue_strip_rect(&self) -> Rect {
+        Rect::new((self.x + 2 * PICKER_PADDING_PX + PICKER_SQUARE_PX) as i32,
+                  (self.y + PICKER_PADDING_PX) as i32,
+                  PICKER_HUE_STRIP_PX as u32,
+                  PICKER_SQUARE_PX as u32)
+    }
+
+    fn slider_rect(&self, index: usize) -> Rect {
+        Rect::new((self.x + PICKER_PADDING_PX) as i32,
+                  (self.y + 2 * PICKER_PADDING_PX + PICKER_SQUARE_PX
+                   + index * (PICKER_SLIDER_HEIGHT_PX + PICKER_PADDING_PX)) as i32,
+                  PICKER_SQUARE_PX as u32,
+                  PICKER_SLIDER_HEIGHT_PX as u32)
+    }
+
+    fn readout_y(&self) -> usize {
+        self.y + 2 * PICKER_PADDING_PX + PICKER_SQUARE_PX
+            + SLIDERS.len() * (PICKER_SLIDER_HEIGHT_PX + PICKER_PADDING_PX)
+    }
+
+    // Clicking it adds the current color to the current palette
+    fn add_button_rect(&self) -> Rect {
+        Rect::new((self.x + 2 * PICKER_PADDING_PX + PICKER_SQUARE_PX) as i32,
+                  self.readout_y() as i32,
+                  PICKER_HUE_STRIP_PX as u32,
+                  PICKER_TEXT_HEIGHT_PX as u32)
+    }
+
+    fn width(&self) -> usize {
+        3 * PICKER_PADDING_PX + PICKER_SQUARE_PX + PICKER_HUE_STRIP_PX
+    }
+
+    fn height(&self) -> usize {
+        self.readout_y() + PICKER_TEXT_HEIGHT_PX + PICKER_PADDING_PX - self.y
+    }
+
+    /*
+     * The current color as hue, saturation, value and alpha, keeping the
+     * last hue for colors without one
+     */
+    fn hsva(&self, color: Color) -> (f32, f32, f32, u8) {
+        let (hue, saturation, value) = color_space::to_hsv(color);
+        let hue = if saturation > 0.0 && value > 0.0 { hue } else { self.hue.get() };
+        (hue, saturation, value, util::color_to_rgba(color).3)
+    }
+
+    /*
+     * The color with `slider` set to `t` (0..1)
+     */
+    fn slider_color(&self, color: Color, slider: Slider, t: f32) -> Color {
+        let (r,g,b,a) = util::color_to_rgba(color);
+        let (hue, saturation, value, _) = self.hsva(color);
+        let byte = (t.max(0.0).min(1.0) * 255.0).round() as u8;
+        let rgba = |r, g, b, a| if a == 255 { Color::RGB(r,g,b) } else { Color::RGBA(r,g,b,a) };
+        match slider {
+            Slider::Red => rgba(byte, g, b, a),
+            Slider::Green => rgba(r, byte, b, a),
+            Slider::Blue => rgba(r, g, byte, a),
+            Slider::Hue => color_space::from_hsv(t * 359.0, saturation, value, a),
+            Slider::Saturation => color_space::from_hsv(hue, t, value, a),
+            Slider::Value => color_space::from_hsv(hue, saturation, t, a),
+            Slider::Alpha => rgba(r, g, b, byte),
+        }
+    }
+
+    /*
+     * Where on the slider (0..1) `color` is
+     */
+    fn slider_position(&self, color: Color, slider: Slider) -> f32 {
+        let (r,g,b,a) = util::color_to_rgba(color);
+        let (hue, saturation, value, _) = self.hsva(color);
+        match slider {
+            Slider::Red => r as f32 / 255.0,
+            Slider::Green => g as f32 / 255.0,
+            Slider::Blue => b as f32 / 255.0,
+            Slider::Hue => hue / 359.0,
+            Slider::Saturation => saturation,
+            Slider::Value => value,
+            Slider::Alpha => a as f32 / 255.0,
+        }
+    }
+}
+
+fn contains(rect: &Rect, x: i32, y: i32) -> bool {
+    rect.x() <= x && x < rect.x() + rect.width() as i32
+        && rect.y() <= y && y < rect.y() + rect.height() as i32
+}
+
+// How far along `rect` (0..1) the point is, horizontally or vertically
+fn fraction(rect: &Rect, x: i32, y: i32) -> (f32, f32) {
+    ((x - rect.x()) as f32 / (rect.width() - 1).max(1) as f32,
+     (y - rect.y()) as f32 / (rect.height() - 1).max(1) as f32)
+}
+
+impl Window for ColorPickerWindow {
+    fn handle_mouse_down(&self, state: &mut State,
+                         mouse_x: i32, mouse_y: i32) {
+        let color = state.current_color;
+        let (hue, _, _, alpha) = self.hsva(color);
+
+        let square = self.square_rect();
+        if contains(&square, mouse_x, mouse_y) {
+            let (saturation, value) = fraction(&square, mouse_x, mouse_y);
+            self.hue.set(hue);
+            state.current_color = color_space::from_hsv(hue, saturation, 1.0 - value, alpha);
+            return;
+        }
+
+        let strip = self.hue_strip_rect();
+        if contains(&strip, mouse_x, mouse_y) {
+            let (_, t) = fraction(&strip, mouse_x, mouse_y);
+            self.hue.set(t * 359.0);
+            state.current_color = self.slider_color(color, Slider::Hue, t);
+            return;
+        }
+
+        for (index, slider) in SLIDERS.iter().enumerate() {
+            let rect = self.slider_rect(index);
+            if contains(&rect, mouse_x, mouse_y) {
+                let (t, _) = fraction(&rect, mouse_x, mouse_y);
+                if let Slider::Hue = *slider {
+                    self.hue.set(t * 359.0);
+                } else {
+                    self.hue.set(hue);
+                }
+                state.current_color = self.slider_color(color, *slider, t);
+                return;
+            }
+        }
+
+        // dragging over the button shouldn't add the color over and over
+        if contains(&self.add_button_rect(), mouse_x, mouse_y)
+            && state.current_palette().last() != Some(&color)
+        {
+            state.current_palette_mut().colors.push(color);
+        }
+    }
+
+    fn draw<'a>(&self, renderer: &mut Renderer<'a>, font: &mut Font, state: &State) {
+        let color = state.current_color;
+        let (hue, saturation, value, _) = self.hsva(color);
+
+        renderer.set_draw_color(Color::RGB(48,48,48));
+        renderer.fill_rect(Rect::new(self.x as i32, self.y as i32,
+                                     self.width() as u32, self.height() as u32)).ok();
+
+        // saturation/value square, drawn in blocks to keep the rect count low
+        let square = self.square_rect();
+        let steps = PICKER_SQUARE_PX / PICKER_SQUARE_STEP_PX;
+        for i in 0..steps {
+            for j in 0..steps {
+                renderer.set_draw_color(color_space::from_hsv(
+                    hue, i as f32 / (steps - 1) as f32, 1.0 - j as f32 / (steps - 1) as f32, 255));
+                renderer.fill_rect(Rect::new(square.x() + (i * PICKER_SQUARE_STEP_PX) as i32,
+                                             square.y() + (j * PICKER_SQUARE_STEP_PX) as i32,
+                                             PICKER_SQUARE_STEP_PX as u32,
+                                             PICKER_SQUARE_STEP_PX as u32)).ok();
+            }
+        }
+        draw_marker(renderer,
+                    square.x() + (saturation * (PICKER_SQUARE_PX - 1) as f32) as i32,
+                    square.y() + ((1.0 - value) * (PICKER_SQUARE_PX - 1) as f32) as i32);
+
+        let strip = self.hue_strip_rect();
+        for j in 0..steps {
+            renderer.set_draw_color(color_space::from_hsv(
+                j as f32 / (steps - 1) as f32 * 359.0, 1.0, 1.0, 255));
+            renderer.fill_rect(Rect::new(strip.x(),
+                                         strip.y() + (j * PICKER_SQUARE_STEP_PX) as i32,
+                                         PICKER_HUE_STRIP_PX as u32,
+                                         PICKER_SQUARE_STEP_PX as u32)).ok();
+        }
+        draw_marker(renderer,
+                    strip.x() + PICKER_HUE_STRIP_PX as i32 / 2,
+                    strip.y() + (hue / 359.0 * (PICKER_SQUARE_PX - 1) as f32) as i32);
+
+        for (index, slider) in SLIDERS.iter().enumerate() {
+            let rect = self.slider_rect(index);
+            let segment = PICKER_SQUARE_PX / PICKER_SLIDER_STEPS;
+            for i in 0..PICKER_SLIDER_STEPS {
+                let t = i as f32 / (PICKER_SLIDER_STEPS - 1) as f32;
+                renderer.set_draw_color(self.slider_color(color, *slider, t));
+                renderer.fill_rect(Rect::new(rect.x() + (i * segment) as i32, rect.y(),
+                                             segment as u32, rect.height())).ok();
+            }
+            let position = self.slider_position(color, *slider);
+            draw_marker(renderer,
+                        rect.x() + (position * (PICKER_SQUARE_PX - 1) as f32) as i32,
+                        rect.y() + PICKER_SLIDER_HEIGHT_PX as i32 / 2);
+        }
+
+        // hex readout on a swatch of the current color
+        let (r,g,b,a) = util::color_to_rgba(color);
+        let text = format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a);
+        renderer.set_draw_color(color);
+        renderer.fill_rect(Rect::new((self.x + PICKER_PADDING_PX) as i32, self.readout_y() as i32,
+                                     PICKER_SQUARE_PX as u32, PICKER_TEXT_HEIGHT_PX as u32)).ok();
+        let text_color = if value > 0.5 && a > 127 { Color::RGB(0,0,0) } else { Color::RGB(255,255,255) };
+        let surface = font.render(&text).blended(text_color).unwrap();
+        let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
+        let (font_width, font_height) = font.size_of(&text).unwrap();
+        renderer.copy(&mut texture, None, Some(Rect::new(
+            (self.x + PICKER_PADDING_PX) as i32, self.readout_y() as i32,
+            font_width / 8, font_height / 8)));
+
+        let button = self.add_button_rect();
+        renderer.set_draw_color(Color::RGB(100,100,100));
+        renderer.fill_rect(button).ok();
+        renderer.set_draw_color(Color::RGB(255,255,255));
+        renderer.fill_rect(Rect::new(button.x() + 3, button.y() + 7, button.width() - 6, 2)).ok();
+        renderer.fill_rect(Rect::new(button.x() + 7, button.y() + 3, 2, button.height() - 6)).ok();
+    }
+}
+
+fn draw_marker<'a>(renderer: &mut Renderer<'a>, x: i32, y: i32) {
+    renderer.set_draw_color(Color::RGB(0,0,0));
+    renderer.draw_rect(Rect::new(x - 3, y - 3, 7, 7)).ok();
+    renderer.set_draw_color(Color::RGB(255,255,255));
+    renderer.draw_rect(Rect::new(x - 2, y - 2, 5, 5)).ok();
+}
@@ -1,51 +1,173 @@
-use sdl2::render::Renderer;
-use sdl2::pixels::Color;
+use sdl2::render::{Renderer, Texture, BlendMode};
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::*;
 use sdl2_ttf::Font;
+use std::cell::RefCell;
 
-use image_buffer::ImageBuffer;
-use state::{State, DrawUndo};
+use image_buffer::{ImageBuffer, DirtyRect};
+use state::{State, DrawUndo, PaintMode};
+use util;
 use windows::Window; 
 
+// Size of the transparency checkerboard squares, in screen pixels
+const CHECKERBOARD_PX: usize = 8;
+const MIN_SCALE: usize = 1;
+const MAX_SCALE: usize = 64;
+// Grid lines closer than this, in screen pixels, would drown the image
+const MIN_GRID_SPACING_PX: usize = 4;
+
+// The texture format whose bytes are R, G, B, A in memory, which SDL names
+// by the packed 32 bit value and so differs with the byte order
+#[cfg(target_endian = "little")]
+const RGBA_BYTES_FORMAT: PixelFormatEnum = PixelFormatEnum::ABGR8888;
+#[cfg(target_endian = "big")]
+const RGBA_BYTES_FORMAT: PixelFormatEnum = PixelFormatEnum::RGBA8888;
+
 /*
  * A window that draws out an image and lets the user edit 
  * said image.
+ *
+ * The window covers `width` x `height` screen pixels at `x`, `y`, and the
+ * image is drawn `offset_x`, `offset_y` pixels from its corner, so it can
+ * be panned around when it doesn't fit.
  */
 pub struct DrawingWindow {
     pub x: usize,
     pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub offset_x: i32,
+    pub offset_y: i32,
     pub scale: usize,
     pub background: Color,
     pub image_id: usize,
+    texture: RefCell<Option<ImageTexture>>,
+}
+
+/*
+ * Streaming texture holding the image, and the image generation it was
+ * last updated to, so only the regions modified since get uploaded.
+ */
+struct ImageTexture {
+    texture: Texture,
+    width: usize,
+    height: usize,
+    generation: u64,
+}
+
+impl ImageTexture {
+    fn new<'a>(renderer: &Renderer<'a>, image: &ImageBuffer) -> ImageTexture {
+        let mut texture = renderer.create_texture_streaming(
+            RGBA_BYTES_FORMAT, image.width as u32, image.height as u32).unwrap();
+        texture.set_blend_mode(BlendMode::Blend);
+        let mut image_texture = ImageTexture {
+            texture: texture,
+            width: image.width,
+            height: image.height,
+            generation: image.generation(),
+        };
+        image_texture.upload(image, DirtyRect::new(0, 0, image.width, image.height));
+        image_texture
+    }
+
+    /*
+     * Uploads whatever changed in `image` since the last update
+     */
+    fn update(&mut self, image: &ImageBuffer) {
+        if let Some(dirty) = image.dirty_since(self.generation) {
+            self.upload(image, dirty);
+        }
+        self.generation = image.generation();
+    }
+
+    fn upload(&mut self, image: &ImageBuffer, rect: DirtyRect) {
+        let DirtyRect { x, y, width, height } = rect;
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for row in y..(y + height) {
+            for column in x..(x + width) {
+                let (r,g,b,a) = util::color_to_rgba(image.get_point(column, row));
+                pixels.extend_from_slice(&[r,g,b,a]);
+            }
+        }
+        self.texture.update(Some(Rect::new(x as i32, y as i32, width as u32, height as u32)),
+                            &pixels, width * 4).ok();
+    }
 }
 
 impl DrawingWindow {
     #[inline(always)]
-    pub fn new(x: usize, y: usize, scale: usize, background: Color,
-               image_id: usize)
+    pub fn new(x: usize, y: usize, width: usize, height: usize, scale: usize,
+               background: Color, image_id: usize)
                -> DrawingWindow
     {
         DrawingWindow {
             x: x,
             y: y,
+            width: width,
+            height: height,
+            offset_x: 0,
+            offset_y: 0,
             scale: scale,
             background: background,
             image_id: image_id,
+            texture: RefCell::new(None),
         }
     }
 
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        /*
+         * Test whether the points `x` and `y` are inside the window,
+         * whether or not there's any image there
+         */
+        let (win_x, win_y) = (self.x as i32, self.y as i32);
+        (win_x <= x
+         && x < win_x + self.width as i32
+         && win_y <= y
+         && y < win_y + self.height as i32)
+    }
+
     pub fn in_range(&self, image: &ImageBuffer, x: i32, y: i32) -> bool {
         /*
          * Test whether a window rendering `image` contains the points
          * `x` and `y`
          */
  
-        let (win_x, win_y, scale) =
-            (self.x as i32, self.y as i32, self.scale as i32);
-        (win_x <= x
-         && x < (win_x + image.width as i32 * scale)
-         && win_y <= y
-         && y < (win_y + image.height as i32 * scale))
+        let (image_x, image_y, scale) = self.image_origin();
+        (self.contains(x, y)
+         && image_x <= x
+         && x < (image_x + image.width as i32 * scale)
+         && image_y <= y
+         && y < (image_y + image.height as i32 * scale))
+    }
+
+    /*
+     * Screen position of the image's top left corner, and the scale
+     */
+    fn image_origin(&self) -> (i32, i32, i32) {
+        (self.x as i32 + self.offset_x,
+         self.y as i32 + self.offset_y,
+         self.scale as i32)
+    }
+
+    /*
+     * Changes the scale while keeping the image point under the screen
+     * point `x`, `y` in place
+     */
+    pub fn zoom_around(&mut self, x: i32, y: i32, scale: usize) {
+        let scale = scale.max(MIN_SCALE).min(MAX_SCALE);
+        let (image_x, image_y, old_scale) = self.image_origin();
+        let (local_x, local_y) = (x - self.x as i32, y - self.y as i32);
+        // image coordinates under the point, kept as fractions of pixels
+        let under_x = (x - image_x) as f32 / old_scale as f32;
+        let under_y = (y - image_y) as f32 / old_scale as f32;
+        self.offset_x = local_x - (under_x * scale as f32).round() as i32;
+        self.offset_y = local_y - (under_y * scale as f32).round() as i32;
+        self.scale = scale;
+    }
+
+    fn center(&self) -> (i32, i32) {
+        (self.x as i32 + self.width as i32 / 2,
+         self.y as i32 + self.height as i32 / 2)
     }
 
     pub fn get_index(&self, image: &ImageBuffer, x: i32, y: i32) ->
@@ -57,10 +179,9 @@ impl DrawingWindow {
          * occurs at point `(x,y)`, which image pixel was targeted?
          */
         if self.in_range(image, x, y) && x >= 0 && y >= 0 {
-            let (win_x, win_y, scale) =
-                (self.x as i32, self.y as i32, self.scale as i32);
-            let index_x = ((x - win_x) / scale) as usize;
-            let index_y = ((y - win_y) / scale) as usize;
+            let (image_x, image_y, scale) = self.image_origin();
+            let index_x = ((x - image_x) / scale) as usize;
+            let index_y = ((y - image_y) / scale) as usize;
             Some((index_x, index_y))
         }
         else { None }
@@ -72,7 +193,7 @@ impl DrawingWindow {
                 .draw_undo
                 .iter()
                 .any(|&DrawUndo {image_id, x, y, color}| {
-                    x == target_x && y == target_y
+                    image_id == self.image_id && x == target_x && y == target_y
                 });
             if !has_previous_undo {
                 undo.draw_undo.push(DrawUndo::new(
@@ -83,6 +204,136 @@ impl DrawingWindow {
             }
         }
     }
+
+    /*
+     * The color a pixel had before the current stroke, if the stroke has
+     * touched it
+     */
+    fn stroke_original(&self, state: &State, target_x: usize, target_y: usize) -> Option<Color> {
+        state.undo_stack.last().and_then(|undo| {
+            undo.draw_undo
+                .iter()
+                .find(|undo| undo.image_id == self.image_id
+                      && undo.x == target_x && undo.y == target_y)
+                .map(|undo| undo.color)
+        })
+    }
+
+    /*
+     * Two tone checkerboard behind the visible part of the image, so
+     * transparency is visible
+     */
+    fn draw_checkerboard<'a>(&self, renderer: &mut Renderer<'a>, visible: Rect) {
+        let (r,g,b,_) = util::color_to_rgba(self.background);
+        let light = Color::RGB(r.saturating_add(40), g.saturating_add(40), b.saturating_add(40));
+        let (image_x, image_y, _) = self.image_origin();
+        let checker = CHECKERBOARD_PX as i32;
+        // squares are aligned to the image corner so they move with it
+        let first_column = (visible.x() - image_x) / checker;
+        let first_row = (visible.y() - image_y) / checker;
+        let last_column = (visible.x() + visible.width() as i32 - 1 - image_x) / checker;
+        let last_row = (visible.y() + visible.height() as i32 - 1 - image_y) / checker;
+        for column in first_column..(last_column + 1) {
+            for row in first_row..(last_row + 1) {
+                renderer.set_draw_color(if (column + row) % 2 == 0 { self.background } else { light });
+                renderer.fill_rect(Rect::new(
+                    image_x + column * checker,
+                    image_y + row * checker,
+                    CHECKERBOARD_PX as u32,
+                    CHECKERBOARD_PX as u32)).ok();
+            }
+        }
+    }
+
+    fn draw_grids<'a>(&self, renderer: &mut Renderer<'a>, state: &State, visible: Rect) {
+        let grid = &state.grid;
+        if grid.show_pixel_grid && self.scale >= MIN_GRID_SPACING_PX {
+            self.draw_grid_lines(renderer, visible, 1, 1, 0, 0, grid.pixel_grid_color);
+        }
+        if grid.show_tile_grid && grid.tile_width > 0 && grid.tile_height > 0
+            && grid.tile_width.min(grid.tile_height) * self.scale >= MIN_GRID_SPACING_PX
+        {
+            self.draw_grid_lines(renderer, visible,
+                                 grid.tile_width, grid.tile_height,
+                                 grid.tile_offset_x, grid.tile_offset_y,
+                                 grid.tile_grid_color);
+        }
+    }
+
+    /*
+     * One pixel wide lines every `spacing_x`, `spacing_y` image pixels,
+     * starting at `offset_x`, `offset_y`, only within `visible`
+     */
+    fn draw_grid_lines<'a>(&self, renderer: &mut Renderer<'a>, visible: Rect,
+                           spacing_x: usize, spacing_y: usize,
+                           offset_x: usize, offset_y: usize, color: Color) {
+        let (image_x, image_y, scale) = self.image_origin();
+        let (left, top) = (visible.x(), visible.y());
+        let (right, bottom) = (left + visible.width() as i32, top + visible.height() as i32);
+        renderer.set_draw_color(color);
+
+        let (step_x, step_y) = (spacing_x as i32 * scale, spacing_y as i32 * scale);
+        let first_x = image_x + (offset_x % spacing_x) as i32 * scale;
+        let first_y = image_y + (offset_y % spacing_y) as i32 * scale;
+        // skip ahead to the first line inside the visible area
+        let mut x = first_x + ((left - first_x) / step_x).max(0) * step_x;
+        while x < right {
+            renderer.fill_rect(Rect::new(x, top, 1, visible.height())).ok();
+            x += step_x;
+        }
+        let mut y = first_y + ((top - first_y) / step_y).max(0) * step_y;
+        while y < bottom {
+            renderer.fill_rect(Rect::new(left, y, visible.width(), 1)).ok();
+            y += step_y;
+        }
+    }
+
+    /*
+     * Outlines the pixel under the canvas cursor, dark inside light so it
+     * shows on any color, and the mark with a single line
+     */
+    fn draw_canvas_cursor<'a>(&self, renderer: &mut Renderer<'a>, state: &State) {
+        let (image_x, image_y, scale) = self.image_origin();
+        let pixel_rect = |x: usize, y: usize, inset: i32| {
+            let size = (scale - 2 * inset).max(1) as u32;
+            Rect::new(image_x + x as i32 * scale + inset, image_y + y as i32 * scale + inset,
+                      size, size)
+        };
+        if let Some((x, y)) = state.mark {
+            renderer.set_draw_color(Color::RGB(255, 200, 0));
+            renderer.draw_rect(pixel_rect(x, y, 0)).ok();
+        }
+        if let Some((x, y)) = state.canvas_cursor {
+            renderer.set_draw_color(Color::RGB(255, 255, 255));
+            renderer.draw_rect(pixel_rect(x, y, 0)).ok();
+            if scale > 2 {
+                renderer.set_draw_color(Color::RGB(0, 0, 0));
+                renderer.draw_rect(pixel_rect(x, y, 1)).ok();
+            }
+        }
+    }
+
+    fn draw_image<'a>(&self, renderer: &mut Renderer<'a>, image: &ImageBuffer, image_rect: Rect) {
+        if image.width == 0 || image.height == 0 {
+            return;
+        }
+
+        let mut cache = self.texture.borrow_mut();
+        let outdated = match *cache {
+            Some(ref texture) => texture.width != image.width || texture.height != image.height,
+            None => true,
+        };
+        if outdated {
+            *cache = Some(ImageTexture::new(renderer, image));
+        } else if let Some(ref mut texture) = *cache {
+            texture.update(image);
+        }
+
+        // the texture blends, so translucent pixels show the checkerboard
+        if let Some(ref texture) = *cache {
+            renderer.copy(&texture.texture, None, Some(image_rect));
+        }
+    }
 }
 
 impl Window for DrawingWindow {
@@ -92,37 +343,96 @@ impl Window for DrawingWindow {
         if let Some((x,y)) = coordinates {
             let color = state.images[self.image_id].get_point(x,y);
             self.save_undo(state, x, y, color);
-            *state.images[self.image_id].get_mut_ref(x, y) = state.current_color; 
+            let new_color = match state.paint_mode {
+                PaintMode::Replace => state.current_color,
+                // Blend against the pixel as it was before this stroke, so
+                // going over it again doesn't darken it further
+                PaintMode::Blend => util::blend_over(
+                    self.stroke_original(state, x, y).unwrap_or(color),
+                    state.current_color,
+                    1.0),
+            };
+            *state.images[self.image_id].get_mut_ref(x, y) = new_color;
         }
     }
 
     fn draw<'a>(&self, renderer: &mut Renderer<'a>, _: &mut Font, state: &State) {
         let image = &state.images[self.image_id];
-        
-        renderer.set_draw_color(self.background);
-        renderer.fill_rect(Rect::new(
-            self.x as i32,
-            self.y as i32,
-            (image.width * self.scale) as u32,
-            (image.height * self.scale) as u32)).ok();
-        
-        for x in 0..image.width {
-            for y in 0..image.height {
-                renderer.set_draw_color(image.get_point(x,y));
-                let (x,y) = (x * self.scale, y * self.scale);
-                renderer.fill_rect(Rect::new(self.x as i32 + x as i32,
-                                             self.y as i32 + y as i32,
-                                             self.scale as u32,
-                                             self.scale as u32)).ok();
-            }
+        let (image_x, image_y, scale) = self.image_origin();
+        let window = Rect::new(self.x as i32, self.y as i32,
+                               self.width as u32, self.height as u32);
+        let image_rect = Rect::new(image_x, image_y,
+                                   image.width as u32 * scale as u32,
+                                   image.height as u32 * scale as u32);
+        let visible = match window.intersection(image_rect) {
+            Some(visible) => visible,
+            None => return,
+        };
+
+        renderer.set_clip_rect(Some(visible));
+        self.draw_checkerboard(renderer, visible);
+        self.draw_image(renderer, image, image_rect);
+        self.draw_grids(renderer, state, visible);
+        if state.active_image_id == Some(self.image_id) {
+            self.draw_canvas_cursor(renderer, state);
         }
+        renderer.set_clip_rect(None);
+    }
+
+    fn image_id(&self) -> Option<usize> {
+        Some(self.image_id)
+    }
+
+    fn contains_point(&self, x: i32, y: i32) -> bool {
+        self.contains(x, y)
+    }
+
+    fn pixel_at(&self, state: &State, x: i32, y: i32) -> Option<(usize, usize, usize)> {
+        self.get_index(&state.images[self.image_id], x, y)
+            .map(|(pixel_x, pixel_y)| (self.image_id, pixel_x, pixel_y))
+    }
+
+    fn scale(&self) -> Option<usize> {
+        Some(self.scale)
     }
 
     fn increment_scale(&mut self) {
-        self.scale = (self.scale + 1) % 32;
+        let (x, y) = self.center();
+        let scale = self.scale + 1;
+        self.zoom_around(x, y, scale);
     }
 
     fn decrement_scale(&mut self) {
-        self.scale = ((self.scale as isize + 31) % 32) as usize;
+        let (x, y) = self.center();
+        let scale = self.scale - 1;
+        self.zoom_around(x, y, scale);
+    }
+
+    fn zoom_at(&mut self, x: i32, y: i32, steps: isize) {
+        let scale = (self.scale as isize + steps).max(MIN_SCALE as isize) as usize;
+        self.zoom_around(x, y, scale);
+    }
+
+    fn pan(&mut self, dx: i32, dy: i32) {
+        self.offset_x = self.offset_x.saturating_add(dx);
+        self.offset_y = self.offset_y.saturating_add(dy);
+    }
+
+    fn fit_to_window(&mut self, state: &State) {
+        let image = &state.images[self.image_id];
+        if image.width == 0 || image.height == 0 {
+            return;
+        }
+        self.scale = (self.width / image.width)
+            .min(self.height / image.height)
+            .max(MIN_SCALE)
+            .min(MAX_SCALE);
+        self.offset_x = (self.width as i32 - (image.width * self.scale) as i32) / 2;
+        self.offset_y = (self.height as i32 - (image.height * self.scale) as i32) / 2;
+    }
+
+    fn actual_size(&mut self) {
+        let (x, y) = self.center();
+        self.zoom_around(x, y, 1);
     }
 }
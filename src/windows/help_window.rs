@@ -0,0 +1,87 @@
+use sdl2::render::Renderer;
+use sdl2::pixels::Color;
+use sdl2::rect::*;
+use sdl2_ttf::Font;
+
+use state::State;
+use windows::Window;
+
+// The font is rendered at 128pt and scaled down by this much
+const FONT_SCALE_DOWN: u32 = 8;
+const LINE_HEIGHT_PX: u32 = 18;
+const PADDING_PX: u32 = 8;
+
+/*
+ * How many lines of help fit in a window `window_height` high, leaving
+ * room for the line saying how to close it
+ */
+pub fn page_lines(window_height: u32) -> usize {
+    let height = window_height.saturating_sub(4 * PADDING_PX) / LINE_HEIGHT_PX;
+    height.saturating_sub(1).max(1) as usize
+}
+
+/*
+ * Shows `State::help_text` over the other windows, a page of it at a
+ * time from `State::help_scroll`, until a key is pressed
+ */
+pub struct HelpWindow {
+    background_color: Color,
+    border_color: Color,
+    font_color: Color,
+}
+
+impl HelpWindow {
+    pub fn new() -> HelpWindow {
+        HelpWindow {
+            background_color: Color::RGB(250, 245, 225),
+            border_color: Color::RGB(240, 210, 120),
+            font_color: Color::RGB(40, 40, 40),
+        }
+    }
+}
+
+impl Window for HelpWindow {
+    fn handle_mouse_down(&self, _: &mut State, _: i32, _: i32) {
+
+    }
+
+    fn draw<'a>(&self, renderer: &mut Renderer<'a>, font: &mut Font, state: &State) {
+        let lines = match state.help_text {
+            Some(ref lines) => lines,
+            None => return,
+        };
+        let (window_width, window_height) = renderer.window().unwrap().size();
+        let first = state.help_scroll.min(lines.len());
+        let shown = &lines[first..(first + page_lines(window_height)).min(lines.len())];
+        let footer = if shown.len() < lines.len() {
+            format!("lines {}-{} of {}, SPC and DEL to scroll, ESC or q to close",
+                    first + 1, first + shown.len(), lines.len())
+        } else {
+            String::from("ESC or q to close")
+        };
+        let width = window_width - 8 * PADDING_PX;
+        let height = (shown.len() as u32 + 1) * LINE_HEIGHT_PX + 2 * PADDING_PX;
+        let rect = Rect::new((4 * PADDING_PX) as i32,
+                             (window_height.saturating_sub(height) / 3) as i32,
+                             width, height);
+        renderer.set_draw_color(self.background_color);
+        renderer.fill_rect(rect).ok();
+        renderer.set_draw_color(self.border_color);
+        renderer.draw_rect(rect).ok();
+
+        renderer.set_clip_rect(Some(rect));
+        let x = rect.x() + PADDING_PX as i32;
+        let mut y = rect.y() + PADDING_PX as i32;
+        for line in shown.iter().chain(Some(footer).iter()) {
+            if !line.is_empty() {
+                let surface = font.render(line).blended(self.font_color).unwrap();
+                let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
+                let (font_width, font_height) = font.size_of(line).unwrap();
+                renderer.copy(&mut texture, None, Some(Rect::new(
+                    x, y, font_width / FONT_SCALE_DOWN, font_height / FONT_SCALE_DOWN)));
+            }
+            y += LINE_HEIGHT_PX as i32;
+        }
+        renderer.set_clip_rect(None);
+    }
+}
@@ -8,11 +8,15 @@ pub mod drawing_window;
 pub mod preview_window;
 pub mod palette_window;
 pub mod status_window;
+pub mod color_picker_window;
+pub mod help_window;
 
 pub use self::drawing_window::DrawingWindow;
 pub use self::preview_window::PreviewWindow;
 pub use self::palette_window::PaletteWindow;
 pub use self::status_window::StatusWindow;
+pub use self::color_picker_window::ColorPickerWindow;
+pub use self::help_window::HelpWindow;
 
 /*
  * Any sort of window that displays, or handles mouse input.
@@ -24,6 +28,17 @@ pub trait Window {
     fn draw<'a>(&self, renderer: &mut Renderer<'a>, font: &mut Font, state: &State);
     fn handle_mouse_down(&self, state: &mut State, mouse_x:
                          i32, mouse_y: i32);
+    fn image_id(&self) -> Option<usize> { None }
+    // Whether a point is inside the window, for routing the mouse wheel
+    // and panning
+    fn contains_point(&self, _x: i32, _y: i32) -> bool { false }
+    // The `(image_id, x, y)` of the image pixel at a screen point
+    fn pixel_at(&self, _state: &State, _x: i32, _y: i32) -> Option<(usize, usize, usize)> { None }
+    fn scale(&self) -> Option<usize> { None }
     fn increment_scale(&mut self) { }
     fn decrement_scale(&mut self) { }
+    fn zoom_at(&mut self, _x: i32, _y: i32, _steps: isize) { }
+    fn pan(&mut self, _dx: i32, _dy: i32) { }
+    fn fit_to_window(&mut self, _state: &State) { }
+    fn actual_size(&mut self) { }
 }
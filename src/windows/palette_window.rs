@@ -6,10 +6,22 @@ use sdl2_ttf::Font;
 use state::State;
 use windows::Window; 
 
+/*
+ * Shows the palette `palette_id`, or the current palette if `None`.
+ */
 pub struct PaletteWindow{
     pub x: usize,
     pub y: usize,
-    pub palette_id: usize,
+    pub palette_id: Option<usize>,
+}
+
+impl PaletteWindow{
+    fn palette<'a>(&self, state: &'a State) -> &'a [Color] {
+        match self.palette_id {
+            Some(id) => state.palettes.get(id).map(|palette| &palette.colors[..]).unwrap_or(&[]),
+            None => state.current_palette(),
+        }
+    }
 }
 
 const PALETTEWINDOW_COLOR_SQUARE_PX: usize = 24;
@@ -22,7 +34,7 @@ impl Window for PaletteWindow{
         let palette_x1 = self.x+PALETTEWINDOW_PADDING_PX;
         let palette_x2 = self.x+PALETTEWINDOW_PADDING_PX + PALETTEWINDOW_COLORS_PER_ROW*PALETTEWINDOW_COLOR_SQUARE_PX;
         let palette_y1 = self.y+PALETTEWINDOW_PADDING_PX;
-        let palette_y2 = self.y+PALETTEWINDOW_PADDING_PX + (state.current_palette().len() as f32/PALETTEWINDOW_COLORS_PER_ROW as f32).ceil() as usize*PALETTEWINDOW_COLOR_SQUARE_PX;
+        let palette_y2 = self.y+PALETTEWINDOW_PADDING_PX + (self.palette(state).len() as f32/PALETTEWINDOW_COLORS_PER_ROW as f32).ceil() as usize*PALETTEWINDOW_COLOR_SQUARE_PX;
 
         if mouse_x as usize>=palette_x1 && mouse_x as usize<=palette_x2
         && mouse_y as usize>=palette_y1 && mouse_y as usize<=palette_y2{
@@ -30,7 +42,7 @@ impl Window for PaletteWindow{
             let palette_y = (mouse_y as usize-palette_y1)/PALETTEWINDOW_COLOR_SQUARE_PX;
             let palette_id = palette_x%PALETTEWINDOW_COLORS_PER_ROW + palette_y*PALETTEWINDOW_COLORS_PER_ROW;
 
-            state.current_color = if let Some(color) = state.current_palette().get(palette_id) {
+            state.current_color = if let Some(color) = self.palette(state).get(palette_id) {
                 *color
             } else { state.current_color }
         }
@@ -42,10 +54,10 @@ impl Window for PaletteWindow{
             self.x as i32,
             self.y as i32,
             (PALETTEWINDOW_COLORS_PER_ROW * PALETTEWINDOW_COLOR_SQUARE_PX + 2*PALETTEWINDOW_PADDING_PX) as u32,
-            ((state.current_palette().len() as f32 / PALETTEWINDOW_COLORS_PER_ROW as f32).ceil() as usize * PALETTEWINDOW_COLOR_SQUARE_PX + 2*PALETTEWINDOW_PADDING_PX) as u32,
+            ((self.palette(state).len() as f32 / PALETTEWINDOW_COLORS_PER_ROW as f32).ceil() as usize * PALETTEWINDOW_COLOR_SQUARE_PX + 2*PALETTEWINDOW_PADDING_PX) as u32,
         )).ok();
 
-        for (i,color) in state.current_palette().iter().enumerate(){
+        for (i,color) in self.palette(state).iter().enumerate(){
             renderer.set_draw_color(*color);
             renderer.fill_rect(Rect::new((PALETTEWINDOW_PADDING_PX as usize + self.x + (i%PALETTEWINDOW_COLORS_PER_ROW as usize)*PALETTEWINDOW_COLOR_SQUARE_PX as usize) as i32,
                                          (PALETTEWINDOW_PADDING_PX as usize + self.y + (i/PALETTEWINDOW_COLORS_PER_ROW as usize)*PALETTEWINDOW_COLOR_SQUARE_PX as usize) as i32,
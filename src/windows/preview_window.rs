@@ -26,4 +26,8 @@ impl Window for PreviewWindow {
         // intentionally left blank
     }
 
+    fn image_id(&self) -> Option<usize> {
+        self.0.image_id()
+    }
+
 }
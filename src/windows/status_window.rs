@@ -3,9 +3,22 @@ use sdl2::pixels::Color;
 use sdl2::rect::*;
 use sdl2_ttf::Font;
 
-use state::State;
-use windows::Window; 
+use state::{State, PaintMode, MessageLevel};
+use util;
+use windows::Window;
 
+// The font is rendered at 128pt and scaled down by this much
+const FONT_SCALE_DOWN: u32 = 8;
+const LINE_HEIGHT_PX: u32 = 18;
+const SWATCH_PX: u32 = 14;
+const PADDING_PX: u32 = 2;
+// Completions past this many are only counted
+const MAX_COMPLETIONS_SHOWN: usize = 12;
+
+/*
+ * The bar at the bottom of the screen. The upper line shows what's under
+ * the mouse and the state of the editor, the lower line the input.
+ */
 pub struct StatusWindow {
     height: u32,
     background_color: Color,
@@ -20,6 +33,93 @@ impl StatusWindow {
             font_color: Color::RGB(40, 40, 40),
         }
     }
+
+    /*
+     * Draws `text` with its top left corner at `x`, `y`, returning how
+     * wide it was
+     */
+    fn draw_text<'a>(&self, renderer: &mut Renderer<'a>, font: &mut Font,
+                     text: &str, color: Color, x: i32, y: i32) -> u32 {
+        if text.is_empty() {
+            return 0;
+        }
+        let surface = font.render(text)
+            .blended(color).unwrap();
+        let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
+
+        let (font_width, font_height) = font.size_of(text).unwrap();
+        let font_rect = Rect::new(
+            x,
+            y,
+            font_width / FONT_SCALE_DOWN,
+            font_height / FONT_SCALE_DOWN);
+
+        renderer.copy(&mut texture, None, Some(font_rect));
+        font_width / FONT_SCALE_DOWN
+    }
+
+    /*
+     * A square of `color` with an outline, so transparent colors are
+     * still visible
+     */
+    fn draw_swatch<'a>(&self, renderer: &mut Renderer<'a>, color: Color, x: i32, y: i32) {
+        renderer.set_draw_color(self.font_color);
+        renderer.draw_rect(Rect::new(x, y, SWATCH_PX, SWATCH_PX)).ok();
+        renderer.set_draw_color(color);
+        renderer.fill_rect(Rect::new(x + 1, y + 1, SWATCH_PX - 2, SWATCH_PX - 2)).ok();
+    }
+
+    fn show_hover(state: &State) -> (String, Option<Color>) {
+        match state.hover {
+            Some((image_id, x, y)) => {
+                let color = state.images[image_id].get_point(x, y);
+                let (r,g,b,a) = util::color_to_rgba(color);
+                (format!("{},{} #{:02x}{:02x}{:02x}{:02x}", x, y, r, g, b, a), Some(color))
+            },
+            None => (String::from("-,-"), None),
+        }
+    }
+
+    fn show_editor_state(state: &State) -> String {
+        let mut parts = vec![];
+        if let Some(image_id) = state.active_image_id {
+            let image = &state.images[image_id];
+            parts.push(format!("{}x{}", image.width, image.height));
+        }
+        if let Some(zoom) = state.zoom {
+            parts.push(format!("{}x zoom", zoom));
+        }
+        parts.push(String::from(match state.paint_mode {
+            PaintMode::Blend => "pencil",
+            PaintMode::Replace => "pencil (replace)",
+        }));
+        if let Some(image_id) = state.active_image_id {
+            match state.layers.iter().position(|layer| layer.image_id == image_id) {
+                Some(index) => parts.push(format!("layer {}/{} {}", index + 1, state.layers.len(),
+                                                  state.layers[index].name)),
+                None => parts.push(format!("image {}/{}", image_id + 1, state.images.len())),
+            }
+        }
+        if let Some((x, y)) = state.canvas_cursor {
+            parts.push(format!("cursor {},{}", x, y));
+        }
+        if state.recording.is_some() {
+            parts.push(String::from("recording macro"));
+        }
+        parts.join(" | ")
+    }
+
+    fn show_completions(state: &State) -> String {
+        let mut text = state.completions.iter()
+            .take(MAX_COMPLETIONS_SHOWN)
+            .map(|candidate| &candidate[..])
+            .collect::<Vec<&str>>()
+            .join("  ");
+        if state.completions.len() > MAX_COMPLETIONS_SHOWN {
+            text = text + &format!("  (+{} more)", state.completions.len() - MAX_COMPLETIONS_SHOWN);
+        }
+        text
+    }
 }
 
 impl Window for StatusWindow {
@@ -29,28 +129,56 @@ impl Window for StatusWindow {
 
     fn draw<'a>(&self, renderer: &mut Renderer<'a>, font: &mut Font, state: &State) {
         let (window_width, window_height) = renderer.window().unwrap().size();
+        let top = (window_height - self.height) as i32;
         let rect = Rect::new(
             0,
-            (window_height - self.height) as i32,
+            top,
             window_width,
             self.height);
-        
+
         renderer.set_draw_color(self.background_color);
         renderer.fill_rect(rect).ok();
 
-        let text = state.show_input_stack();
-        let surface = font.render(&text)
-            .blended(self.font_color).unwrap();
-        let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
+        // the candidates of the last completion get a line above the bar
+        if !state.completions.is_empty() {
+            let completions_top = top - LINE_HEIGHT_PX as i32;
+            renderer.set_draw_color(self.background_color);
+            renderer.fill_rect(Rect::new(0, completions_top, window_width, LINE_HEIGHT_PX)).ok();
+            self.draw_text(renderer, font, &StatusWindow::show_completions(state),
+                           self.font_color, PADDING_PX as i32, completions_top);
+        }
 
-        let (font_width, font_height) = font.size_of(&text).unwrap();
-        let font_rect = Rect::new(
-            0,
-            (window_height - self.height) as i32,
-            font_width / 8, 
-            font_height / 8);
+        // upper line: active color, hovered pixel, then the editor state
+        let mut x = PADDING_PX as i32;
+        let swatch_y = top + PADDING_PX as i32;
+        self.draw_swatch(renderer, state.current_color, x, swatch_y);
+        x += (SWATCH_PX + 2 * PADDING_PX) as i32;
 
-        renderer.copy(&mut texture, None, Some(font_rect));
+        let (hover_text, hover_color) = StatusWindow::show_hover(state);
+        if let Some(color) = hover_color {
+            self.draw_swatch(renderer, color, x, swatch_y);
+        }
+        x += (SWATCH_PX + 2 * PADDING_PX) as i32;
+        x += self.draw_text(renderer, font, &hover_text, self.font_color, x, top) as i32;
+        x += (4 * PADDING_PX) as i32;
+        self.draw_text(renderer, font, &StatusWindow::show_editor_state(state),
+                       self.font_color, x, top);
+
+        // lower line: the input, followed by the latest message
+        let line_y = top + LINE_HEIGHT_PX as i32;
+        let text = state.show_input_stack();
+        let input_width = self.draw_text(renderer, font, &text, self.font_color, 0, line_y);
+        if let Some(ref message) = state.message {
+            if !message.is_expired() {
+                let color = match message.level {
+                    MessageLevel::Info => self.font_color,
+                    MessageLevel::Warning => Color::RGB(140, 80, 0),
+                    MessageLevel::Error => Color::RGB(180, 20, 20),
+                };
+                self.draw_text(renderer, font, &message.text, color,
+                               (input_width + 4 * PADDING_PX) as i32, line_y);
+            }
+        }
         renderer.present();
     }
 }